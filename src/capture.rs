@@ -0,0 +1,267 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{SyncSender, sync_channel},
+    },
+    thread::JoinHandle,
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::display::Resolution;
+
+/// Image file formats frames are saved in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    /// Format of a file, from its extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("png") => Ok(Self::Png),
+            Some("ppm") => Ok(Self::Ppm),
+            _ => bail!(
+                "[capture] unknown image format of {}, expected .png or .ppm",
+                path.display()
+            ),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Ppm => "ppm",
+        }
+    }
+}
+
+/// Write an RGBA frame of the display to `path`, the format is taken from the extension.
+pub fn save_image(path: &Path, resolution: Resolution, frame: &[u8]) -> Result<()> {
+    let format = ImageFormat::from_path(path)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let data = match format {
+        ImageFormat::Png => encode_png(resolution, frame)?,
+        ImageFormat::Ppm => encode_ppm(resolution, frame),
+    };
+    std::fs::write(path, data).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Binary PPM, the alpha channel is dropped.
+fn encode_ppm(resolution: Resolution, frame: &[u8]) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", resolution.width, resolution.height).into_bytes();
+    for rgba in frame.chunks_exact(4) {
+        out.extend_from_slice(&rgba[..3]);
+    }
+    out
+}
+
+/// 8 bit RGB PNG, compressed with the fastest setting that still compresses well.
+fn encode_png(resolution: Resolution, frame: &[u8]) -> Result<Vec<u8>> {
+    let rgb: Vec<u8> = frame
+        .chunks_exact(4)
+        .flat_map(|rgba| [rgba[0], rgba[1], rgba[2]])
+        .collect();
+    let mut out = Vec::new();
+    let mut encoder =
+        png::Encoder::new(&mut out, resolution.width as u32, resolution.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Fast);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&rgb)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+/// Settings for saving every Nth presented frame
+pub struct Recording {
+    pub dir: PathBuf,
+    pub format: ImageFormat,
+    pub every: u64,
+}
+
+/// Recorded frames waiting to be written, at most this many before the display waits
+const QUEUED_FRAMES: usize = 8;
+
+/// Encodes and writes recorded frames on a thread of its own, so the display isn't held up
+/// unless the disk can't keep up.
+struct Writer {
+    send: Option<SyncSender<(PathBuf, Vec<u8>)>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Writer {
+    fn spawn(resolution: Resolution) -> Self {
+        let (send, recv) = sync_channel::<(PathBuf, Vec<u8>)>(QUEUED_FRAMES);
+        let thread = std::thread::Builder::new()
+            .name("recording".into())
+            .spawn(move || {
+                for (path, frame) in recv {
+                    save_image(&path, resolution, &frame)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        Self {
+            send: Some(send),
+            thread: Some(thread),
+        }
+    }
+
+    fn write(&mut self, path: PathBuf, frame: Vec<u8>) -> Result<()> {
+        let Some(send) = &self.send else {
+            return Ok(());
+        };
+        if send.send((path, frame)).is_err() {
+            // The thread stopped at an error
+            return self.finish();
+        }
+        Ok(())
+    }
+
+    /// Wait until the queued frames are written.
+    fn finish(&mut self) -> Result<()> {
+        self.send = None;
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Saves frames of the display for bug reports and docs.
+///
+/// Files are named after the frame number and the instruction count at the time,
+/// e.g. `frame-000120-insn-4096000.png`.
+pub struct Capture {
+    resolution: Resolution,
+    /// Instruction count of the CPU
+    insn_count: Arc<AtomicU64>,
+    /// Where the screenshot hotkey saves to
+    pub screenshot_dir: PathBuf,
+    /// Saved with the last frame when the display closes
+    pub exit_screenshot: Option<PathBuf>,
+    pub recording: Option<Recording>,
+    /// Started with the first recorded frame
+    writer: Option<Writer>,
+}
+
+impl Capture {
+    pub fn new(resolution: Resolution, insn_count: Arc<AtomicU64>) -> Self {
+        Self {
+            resolution,
+            insn_count,
+            screenshot_dir: PathBuf::from("screenshots"),
+            exit_screenshot: None,
+            recording: None,
+            writer: None,
+        }
+    }
+
+    fn file_name(&self, frame_number: u64, format: ImageFormat) -> String {
+        format!(
+            "frame-{frame_number:06}-insn-{}.{}",
+            self.insn_count.load(Ordering::Relaxed),
+            format.extension()
+        )
+    }
+
+    /// Save the frame to the screenshot directory.
+    pub fn screenshot(&self, frame: &[u8], frame_number: u64) -> Result<()> {
+        let path = self
+            .screenshot_dir
+            .join(self.file_name(frame_number, ImageFormat::Png));
+        save_image(&path, self.resolution, frame)
+    }
+
+    /// Called after every presented frame, queues it to be saved if it is recorded.
+    pub fn presented(&mut self, frame: &[u8], frame_number: u64) -> Result<()> {
+        let Some(recording) = &self.recording else {
+            return Ok(());
+        };
+        if !frame_number.is_multiple_of(recording.every) {
+            return Ok(());
+        }
+        let path = recording
+            .dir
+            .join(self.file_name(frame_number, recording.format));
+        let resolution = self.resolution;
+        self.writer
+            .get_or_insert_with(|| Writer::spawn(resolution))
+            .write(path, frame.to_vec())
+    }
+
+    /// Called when the display closes, waits for the recorded frames to be written.
+    pub fn exit(&mut self, frame: &[u8]) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.finish()?;
+        }
+        match &self.exit_screenshot {
+            Some(path) => save_image(path, self.resolution, frame),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_round_trip() {
+        let resolution = Resolution {
+            width: 200,
+            height: 120,
+        };
+        let frame: Vec<u8> = (0..resolution.pixels() as u32)
+            .flat_map(|i| [i as u8, (i >> 8) as u8, (i * 7) as u8, 255])
+            .collect();
+        let png = encode_png(resolution, &frame).unwrap();
+
+        let mut reader = png::Decoder::new(std::io::Cursor::new(png))
+            .read_info()
+            .unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+        assert_eq!((info.width, info.height), (200, 120));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        let expected: Vec<u8> = frame
+            .chunks(4)
+            .flat_map(|rgba| rgba[..3].to_vec())
+            .collect();
+        assert_eq!(rgb, expected);
+    }
+
+    #[test]
+    fn png_is_compressed() {
+        let resolution = Resolution::DEFAULT;
+        let frame: Vec<u8> = (0..resolution.pixels())
+            .flat_map(|i| [(i / 64) as u8, 0, 0, 255])
+            .collect();
+        let png = encode_png(resolution, &frame).unwrap();
+        assert!(png.len() < resolution.pixels() / 10, "{} bytes", png.len());
+    }
+
+    #[test]
+    fn ppm() {
+        let resolution = Resolution {
+            width: 1,
+            height: 2,
+        };
+        let ppm = encode_ppm(resolution, &[1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_eq!(ppm, b"P6\n1 2\n255\n\x01\x02\x03\x04\x05\x06");
+    }
+}
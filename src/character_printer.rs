@@ -1,8 +1,12 @@
-use std::sync::mpsc::Sender;
-
 use anyhow::{Result, bail};
 
-use crate::{csrs::Csr, display::DisplayEvent};
+use crate::{
+    cpu_thread::memory::Memory,
+    csrs::Csr,
+    display::DisplayEvent,
+    framebuffer::DrawTarget,
+    savestate::{StateReader, StateWriter},
+};
 
 #[rustfmt::skip]
 const FONT: &[&[(u8, u8)]] = &[
@@ -103,72 +107,181 @@ const FONT: &[&[(u8, u8)]] = &[
     &[(1, 2), (4, 2), (0, 3), (2, 3), (3, 3)],
 ];
 
+/// Horizontal distance between characters
+const ADVANCE: u16 = 6;
+/// Width of the glyphs in the font
+const GLYPH_WIDTH: u16 = 5;
+const DEFAULT_LINE_HEIGHT: u16 = 8;
+/// Tab stops are this many characters apart, counted from the left margin
+const TAB_WIDTH: u16 = 4;
+
+/// Prints text with a 5x7 font.
+///
+/// - 1024: color, 0xRRGGBB
+/// - 1025: cursor, x in the low and y in the high half word. Writing it also sets the
+///   left margin that new lines start at to x. Reads return the cursor
+/// - 1026: print the bytes at this RAM address, `\n` starts a new line, `\r` returns
+///   to the left margin, `\t` advances to the next tab stop and backspace moves back
+///   one character without erasing it
+/// - 1027: right margin, text that would cross it continues on the next line,
+///   0 for the right edge of the screen
+/// - 1028: line height in pixels, 8 by default
+/// - 1037: number of bytes 1026 prints
+///
+/// 1027 and 1028 can be read back too.
 pub struct CharacterPrinterCsr {
-    send: Sender<DisplayEvent>,
+    output: DrawTarget,
     length: usize,
     target_x: u16,
     target_y: u16,
     color: u32,
+    left_margin: u16,
+    /// 0 for the right edge of the screen
+    right_margin: u16,
+    line_height: u16,
 }
 
 impl CharacterPrinterCsr {
-    pub fn new(send: Sender<DisplayEvent>) -> Self {
+    pub fn new(output: DrawTarget) -> Self {
         Self {
-            send,
+            output,
             length: 0,
             target_x: 0,
             target_y: 0,
             color: 0,
+            left_margin: 0,
+            right_margin: 0,
+            line_height: DEFAULT_LINE_HEIGHT,
         }
     }
 
-    pub fn send_char(&mut self, c: u8) {
-        if !(c >= 0x20 && c < 0x7F) {
-            self.target_x += 6;
+    fn cursor(&self) -> u32 {
+        (self.target_y as u32) << 16 | self.target_x as u32
+    }
+
+    fn new_line(&mut self) {
+        self.target_x = self.left_margin;
+        self.target_y = self.target_y.saturating_add(self.line_height);
+    }
+
+    pub fn send_char(&mut self, mem: &mut Memory, c: u8) {
+        match c {
+            b'\n' => return self.new_line(),
+            b'\r' => {
+                self.target_x = self.left_margin;
+                return;
+            }
+            b'\t' => {
+                let tab = ADVANCE * TAB_WIDTH;
+                let column = self.target_x.saturating_sub(self.left_margin) / tab + 1;
+                self.target_x = self.left_margin.saturating_add(column.saturating_mul(tab));
+                return;
+            }
+            0x08 => {
+                self.target_x = self.target_x.saturating_sub(ADVANCE).max(self.left_margin);
+                return;
+            }
+            _ => {}
+        }
+        if !(0x20..0x7F).contains(&c) {
+            self.target_x = self.target_x.saturating_add(ADVANCE);
             return;
         }
 
+        let right_margin = match self.right_margin {
+            0 => self.output.resolution().width,
+            margin => margin,
+        };
+        // A line always takes at least one character, even if the margins leave no room
+        if self.target_x > self.left_margin
+            && self.target_x.saturating_add(GLYPH_WIDTH) > right_margin
+        {
+            self.new_line();
+        }
+
         let c = (c - 0x20) as usize;
-        let pixels = FONT[c as usize];
+        let pixels = FONT[c];
         let mut matrix = 0u64;
         for &(x, y) in pixels {
             let idx = y * 8 + x;
             matrix |= 1 << idx;
         }
-        self.send
-            .send(DisplayEvent::Matrix {
+        self.output.draw(
+            mem,
+            DisplayEvent::Matrix {
                 matrix,
                 target_x: self.target_x,
                 target_y: self.target_y,
                 color: self.color,
-            })
-            .unwrap();
-        self.target_x += 6;
+            },
+        );
+        self.target_x = self.target_x.saturating_add(ADVANCE);
     }
 }
 
 impl Csr for CharacterPrinterCsr {
-    fn read(&mut self, _csr: u32, _ram: &mut [u8]) -> Result<u32> {
-        bail!("Can't read from character printer")
+    fn name(&self) -> &'static str {
+        "character_printer"
     }
 
-    fn write(&mut self, csr: u32, ram: &mut [u8], data: u32) -> Result<()> {
+    fn read(&mut self, csr: u32, _mem: &mut Memory) -> Result<u32> {
+        match csr {
+            1025 => Ok(self.cursor()),
+            1027 => Ok(self.right_margin as u32),
+            1028 => Ok(self.line_height as u32),
+            _ => bail!("Can't read from character printer"),
+        }
+    }
+
+    fn write(&mut self, csr: u32, mem: &mut Memory, data: u32) -> Result<()> {
         match csr {
             1024 => self.color = data,
             1025 => {
                 self.target_x = (data & 0xFFFF) as u16;
                 self.target_y = (data >> 16) as u16;
+                self.left_margin = self.target_x;
             }
             1026 => {
                 let addr = data as usize;
-                let data = &ram[addr..addr + self.length];
+                let data = mem.vec[addr..addr + self.length].to_vec();
                 for c in data {
-                    self.send_char(*c);
+                    self.send_char(mem, c);
                 }
             }
+            1027 => self.right_margin = data as u16,
+            1028 => self.line_height = data as u16,
             1037 => self.length = data as usize,
             _ => unreachable!(),
         }
         Ok(())
     }
+
+    fn reset(&mut self) {
+        self.target_x = 0;
+        self.target_y = 0;
+        self.left_margin = 0;
+        self.right_margin = 0;
+        self.line_height = DEFAULT_LINE_HEIGHT;
+    }
+
+    fn save(&mut self, w: &mut StateWriter) {
+        w.u32(self.length as u32);
+        w.u32(self.cursor());
+        w.u32(self.color);
+        w.u32(self.left_margin as u32);
+        w.u32(self.right_margin as u32);
+        w.u32(self.line_height as u32);
+    }
+
+    fn load(&mut self, r: &mut StateReader, _mem: &Memory) -> Result<()> {
+        self.length = r.u32()? as usize;
+        let cursor = r.u32()?;
+        self.target_x = cursor as u16;
+        self.target_y = (cursor >> 16) as u16;
+        self.color = r.u32()?;
+        self.left_margin = r.u32()? as u16;
+        self.right_margin = r.u32()? as u16;
+        self.line_height = r.u32()? as u16;
+        Ok(())
+    }
 }
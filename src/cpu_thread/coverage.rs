@@ -0,0 +1,230 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+
+use super::{
+    cpu::Cpu,
+    hook::{Commit, Hook},
+    memory::MemAccessSize,
+};
+
+/// Source file used for programs without line info, its line numbers are addresses
+const NO_LINE_INFO: &str = "[program]";
+
+/// Records executed instructions and branch edges.
+///
+/// The lcov report maps addresses to source lines when the ELF file has DWARF line info.
+pub struct Coverage {
+    /// Reports are written to `<prefix>.info` in lcov format and `<prefix>.txt`
+    pub prefix: PathBuf,
+    /// Executions per pc
+    counts: HashMap<u32, u64>,
+    /// Taken and not taken counts per conditional branch
+    branches: HashMap<u32, (u64, u64)>,
+}
+
+/// Coverage of one source file
+#[derive(Default)]
+struct FileCoverage {
+    /// Highest execution count of the instructions on each line
+    lines: BTreeMap<u32, u64>,
+    branches: Vec<Branch>,
+    /// Line, name and number of calls of each function
+    functions: Vec<(u32, String, u64)>,
+}
+
+/// A conditional branch in a source file
+struct Branch {
+    line: u32,
+    pc: u32,
+    /// Taken and not taken counts, `None` if never executed
+    counts: Option<(u64, u64)>,
+}
+
+/// Coverage of one function for the summary
+#[derive(Default)]
+struct FunctionSummary {
+    name: String,
+    executed: u64,
+    instructions: u64,
+    edges_hit: u64,
+    edges: u64,
+}
+
+impl Coverage {
+    pub fn new(prefix: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            counts: HashMap::new(),
+            branches: HashMap::new(),
+        }
+    }
+
+    /// Write the lcov report and the per-function summary.
+    pub fn write(&self, cpu: &Cpu) -> Result<()> {
+        // Every instruction of the program, not only the executed ones
+        let mut instructions: BTreeSet<u32> = self.counts.keys().copied().collect();
+        for function in cpu.symbols.functions() {
+            instructions.extend((function.addr..function.addr + function.size).step_by(4));
+        }
+        let is_branch = |pc| {
+            cpu.mem
+                .read(pc, MemAccessSize::Word)
+                .is_ok_and(|insn| insn & 0x7F == 0b1100011)
+        };
+
+        let lines = &cpu.symbols.lines;
+        let location = |pc: u32| match lines.is_empty() {
+            true => Some((NO_LINE_INFO, pc)),
+            false => lines.lookup(pc),
+        };
+
+        let mut files: BTreeMap<&str, FileCoverage> = BTreeMap::new();
+        let mut summaries: BTreeMap<u32, FunctionSummary> = BTreeMap::new();
+        for &pc in &instructions {
+            let count = self.counts.get(&pc).copied().unwrap_or(0);
+            let branch = is_branch(pc).then(|| self.branches.get(&pc).copied());
+
+            let (addr, name) = match cpu.symbols.lookup(pc) {
+                Some(symbol) => (symbol.addr, symbol.name.as_str()),
+                None => (u32::MAX, "[no symbol]"),
+            };
+            let summary = summaries.entry(addr).or_default();
+            summary.name = name.into();
+            summary.instructions += 1;
+            summary.executed += (count != 0) as u64;
+            if let Some(branch) = branch {
+                let (taken, not_taken) = branch.unwrap_or_default();
+                summary.edges += 2;
+                summary.edges_hit += (taken != 0) as u64 + (not_taken != 0) as u64;
+            }
+
+            let Some((file, line)) = location(pc) else {
+                continue;
+            };
+            let file = files.entry(file).or_default();
+            let line_count = file.lines.entry(line).or_default();
+            *line_count = (*line_count).max(count);
+            if let Some(counts) = branch {
+                file.branches.push(Branch { line, pc, counts });
+            }
+        }
+        for function in cpu.symbols.functions() {
+            if let Some((file, line)) = location(function.addr) {
+                let calls = self.counts.get(&function.addr).copied().unwrap_or(0);
+                files
+                    .entry(file)
+                    .or_default()
+                    .functions
+                    .push((line, function.name.clone(), calls));
+            }
+        }
+
+        let info_path = self.prefix.with_extension("info");
+        std::fs::write(&info_path, lcov(&files))
+            .with_context(|| format!("failed to write {}", info_path.display()))?;
+        let summary_path = self.prefix.with_extension("txt");
+        std::fs::write(&summary_path, summary(&summaries))
+            .with_context(|| format!("failed to write {}", summary_path.display()))
+    }
+}
+
+fn lcov(files: &BTreeMap<&str, FileCoverage>) -> String {
+    let mut r = String::new();
+    for (name, file) in files {
+        writeln!(r, "TN:").unwrap();
+        writeln!(r, "SF:{name}").unwrap();
+        for (line, name, _) in &file.functions {
+            writeln!(r, "FN:{line},{name}").unwrap();
+        }
+        for (_, name, calls) in &file.functions {
+            writeln!(r, "FNDA:{calls},{name}").unwrap();
+        }
+        let hit = file.functions.iter().filter(|f| f.2 != 0).count();
+        writeln!(r, "FNF:{}", file.functions.len()).unwrap();
+        writeln!(r, "FNH:{hit}").unwrap();
+
+        let mut edges_hit = 0;
+        for Branch { line, pc, counts } in &file.branches {
+            let edges = match counts {
+                Some((taken, not_taken)) => [taken.to_string(), not_taken.to_string()],
+                None => ["-".into(), "-".into()],
+            };
+            for (i, edge) in edges.iter().enumerate() {
+                writeln!(r, "BRDA:{line},{pc},{i},{edge}").unwrap();
+                edges_hit += (edge != "-" && edge != "0") as usize;
+            }
+        }
+        writeln!(r, "BRF:{}", file.branches.len() * 2).unwrap();
+        writeln!(r, "BRH:{edges_hit}").unwrap();
+
+        for (line, count) in &file.lines {
+            writeln!(r, "DA:{line},{count}").unwrap();
+        }
+        let hit = file.lines.values().filter(|&&count| count != 0).count();
+        writeln!(r, "LF:{}", file.lines.len()).unwrap();
+        writeln!(r, "LH:{hit}").unwrap();
+        writeln!(r, "end_of_record").unwrap();
+    }
+    r
+}
+
+fn summary(functions: &BTreeMap<u32, FunctionSummary>) -> String {
+    let percent = |hit: u64, total: u64| 100.0 * hit as f64 / total.max(1) as f64;
+    let executed: u64 = functions.values().map(|f| f.executed).sum();
+    let instructions: u64 = functions.values().map(|f| f.instructions).sum();
+    let edges_hit: u64 = functions.values().map(|f| f.edges_hit).sum();
+    let edges: u64 = functions.values().map(|f| f.edges).sum();
+
+    let mut r = String::new();
+    writeln!(
+        r,
+        "{executed}/{instructions} instructions ({:.2}%), {edges_hit}/{edges} branch edges ({:.2}%)",
+        percent(executed, instructions),
+        percent(edges_hit, edges)
+    )
+    .unwrap();
+    writeln!(r).unwrap();
+    writeln!(
+        r,
+        "{:>8} {:>13} {:>8} {:>11}  function",
+        "insn%", "insns", "branch%", "edges"
+    )
+    .unwrap();
+    for f in functions.values() {
+        writeln!(
+            r,
+            "{:>7.2}% {:>13} {:>7.2}% {:>11}  {}",
+            percent(f.executed, f.instructions),
+            format!("{}/{}", f.executed, f.instructions),
+            percent(f.edges_hit, f.edges),
+            format!("{}/{}", f.edges_hit, f.edges),
+            f.name
+        )
+        .unwrap();
+    }
+    r
+}
+
+impl Hook for Coverage {
+    fn retire(&mut self, _cpu: &Cpu, commit: &Commit) -> Result<()> {
+        *self.counts.entry(commit.pc).or_default() += 1;
+        if let Some(taken) = commit.branch_taken {
+            let counts = self.branches.entry(commit.pc).or_default();
+            if taken {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, cpu: &Cpu) -> Result<()> {
+        self.write(cpu)
+    }
+}
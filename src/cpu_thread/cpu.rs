@@ -1,11 +1,107 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
 use anyhow::{Result, bail};
 use fps_counter::FPSCounter;
 
 use super::{
+    hook::{Commit, Hook},
     instruction_formats::{BType, IType, JType, RType, SType, UType},
     memory::{MemAccessSize, Memory},
 };
-use crate::csrs::Csrs;
+use crate::{
+    csrs::Csrs,
+    device_log::DeviceLog,
+    elf::{Elf, Symbols},
+};
+
+/// ABI names of the integer registers, indexed by register number
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Number of recently executed pcs kept for crash reports
+pub const RECENT_PCS: usize = 32;
+
+/// Look up a register by its `xN` or ABI name.
+pub fn register_index(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(idx) = name.strip_prefix('x').and_then(|n| n.parse().ok()) {
+        return (idx < 32).then_some(idx);
+    }
+    ABI_NAMES.iter().position(|&n| n == name)
+}
+
+/// Region `sp` has to stay in, and the deepest `sp` seen in it
+#[derive(Clone, Copy)]
+pub struct Stack {
+    pub start: u32,
+    /// Initial `sp`, the stack grows down from here
+    pub end: u32,
+    pub lowest: u32,
+    /// Whether `sp` was in the region yet, startup code builds it in several steps
+    pub entered: bool,
+}
+
+impl Stack {
+    pub fn new(start: u32, end: u32) -> Self {
+        Self {
+            start,
+            end,
+            lowest: end,
+            entered: false,
+        }
+    }
+
+    /// Most bytes used so far.
+    pub fn used(&self) -> u32 {
+        self.end - self.lowest
+    }
+}
+
+pub const MSTATUS: u32 = 0x300;
+pub const MTVEC: u32 = 0x305;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+
+/// Interrupt bit and cause of a machine external interrupt
+const EXTERNAL_INTERRUPT: u32 = 0x8000_000B;
+const MRET: u32 = 0x30200073;
+
+/// Machine mode trap state, only external interrupts raised by devices are supported
+#[derive(Default, Clone, Copy, PartialEq)]
+pub struct Trap {
+    /// mstatus.MIE
+    pub enabled: bool,
+    /// mstatus.MPIE, `enabled` before the trap
+    pub prev_enabled: bool,
+    pub mtvec: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    /// A device raised an interrupt that wasn't taken yet
+    pub pending: bool,
+}
+
+impl Trap {
+    pub fn mstatus(&self) -> u32 {
+        (self.enabled as u32) << 3 | (self.prev_enabled as u32) << 7
+    }
+
+    pub fn set_mstatus(&mut self, data: u32) {
+        self.enabled = data & (1 << 3) != 0;
+        self.prev_enabled = data & (1 << 7) != 0;
+    }
+}
 
 pub struct Cpu {
     pub registers: [u32; 32],
@@ -13,8 +109,35 @@ pub struct Cpu {
     pub csrs: Csrs,
     pub mem: Memory,
     pub insn_count: u64,
+    /// Instruction count for other threads, updated every few hundred instructions
+    pub shared_insn_count: Arc<AtomicU64>,
     pub fps_counter: FPSCounter,
     pub fps: usize,
+    /// Addresses the CPU thread stops at before executing them
+    pub breakpoints: HashSet<u32>,
+    /// Addresses the CPU thread stops at after a store to them
+    pub watchpoints: HashSet<u32>,
+    /// Last flashed image, flashed again on reset
+    image: Vec<u8>,
+    /// Pc after reset
+    entry: u32,
+    /// Symbols of the loaded program, empty for raw images
+    pub symbols: Arc<Symbols>,
+    /// Stack region set explicitly or from the symbols, `sp` is checked against it
+    pub stack: Option<Stack>,
+    /// Effects of the last retired instruction
+    pub commit: Commit,
+    /// Pcs of the last [`RECENT_PCS`] retired instructions, oldest first
+    pub recent_pcs: VecDeque<u32>,
+    pub trap: Trap,
+    /// Loads of memory that was never written, by pc: the first address read and the
+    /// number of reads. Only tracked while [`Memory::track_initialized`] is on
+    pub uninitialized_reads: BTreeMap<u32, (u32, u64)>,
+    /// Stop at loads of uninitialized memory instead of only reporting them
+    pub stop_on_uninitialized: bool,
+    /// New uninitialized reads are reported here
+    pub device_log: DeviceLog,
+    hooks: Vec<Box<dyn Hook>>,
 }
 
 impl Cpu {
@@ -25,20 +148,127 @@ impl Cpu {
             csrs,
             mem: Memory::new(16 * 1024 * 1024),
             insn_count: 0,
+            shared_insn_count: Arc::new(AtomicU64::new(0)),
             fps_counter: FPSCounter::new(),
             fps: 0,
+            breakpoints: HashSet::new(),
+            watchpoints: HashSet::new(),
+            image: vec![],
+            entry: 0,
+            symbols: Arc::default(),
+            stack: None,
+            commit: Commit::default(),
+            recent_pcs: VecDeque::with_capacity(RECENT_PCS),
+            trap: Trap::default(),
+            uninitialized_reads: BTreeMap::new(),
+            stop_on_uninitialized: false,
+            device_log: DeviceLog::default(),
+            hooks: vec![],
         }
     }
 
     pub fn flash(&mut self, data: &[u8]) {
         self.mem.flash(data);
+        self.image = data.to_vec();
+        self.entry = 0;
+        self.symbols = Arc::default();
+    }
+
+    /// Flash the segments of an ELF file and start at its entry point.
+    pub fn load_elf(&mut self, elf: Elf) -> Result<()> {
+        if elf.image.len() > self.mem.vec.len() {
+            bail!("[elf] program doesn't fit in memory");
+        }
+        self.flash(&elf.image);
+        self.entry = elf.entry;
+        self.pc = elf.entry;
+        if let Some((start, end)) = elf.symbols.stack_region() {
+            self.stack = Some(Stack::new(start, end));
+        }
+        self.symbols = Arc::new(elf.symbols);
+        Ok(())
+    }
+
+    /// Let hooks share their state with the GUI.
+    pub fn publish(&mut self) {
+        self.shared_insn_count
+            .store(self.insn_count, Ordering::Relaxed);
+        for hook in &mut self.hooks {
+            hook.publish();
+        }
+    }
+
+    /// Let every hook finish its work, e.g. write reports.
+    pub fn finish(&mut self) -> Result<()> {
+        let mut hooks = std::mem::take(&mut self.hooks);
+        let result = hooks.iter_mut().try_for_each(|hook| hook.finish(self));
+        self.hooks = hooks;
+        result
+    }
+
+    /// Clear registers, counters and memory, then flash the last image again.
+    pub fn reset(&mut self) {
+        self.reset_cpu();
+        self.csrs.reset();
+    }
+
+    /// [`Cpu::reset`] without resetting the devices.
+    pub fn reset_cpu(&mut self) {
+        self.registers = [0; 32];
+        self.pc = self.entry;
+        self.insn_count = 0;
+        self.recent_pcs.clear();
+        self.trap = Trap::default();
+        if let Some(stack) = &mut self.stack {
+            stack.lowest = stack.end;
+            stack.entered = false;
+        }
+        self.mem.clear();
+        self.mem.flash(&self.image);
+        self.mem.clear_poison();
+        for hook in &mut self.hooks {
+            hook.reset();
+        }
+    }
+
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    /// The first hook of type `T`, if any.
+    pub fn hook<T: Hook>(&self) -> Option<&T> {
+        self.hooks
+            .iter()
+            .find_map(|hook| (hook.as_ref() as &dyn Any).downcast_ref())
     }
 
-    fn write_register(&mut self, reg_index: usize, data: u32) {
+    /// The first hook of type `T`, if any.
+    pub fn hook_mut<T: Hook>(&mut self) -> Option<&mut T> {
+        self.hooks
+            .iter_mut()
+            .find_map(|hook| (hook.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Remove all hooks of type `T`.
+    pub fn remove_hooks<T: Hook>(&mut self) {
+        self.hooks
+            .retain(|hook| !(hook.as_ref() as &dyn Any).is::<T>());
+    }
+
+    /// Whether the last retired instruction stored to a watched address.
+    pub fn hit_watchpoint(&self) -> bool {
+        self.commit
+            .store
+            .is_some_and(|(addr, size, _)| stores_to(&self.watchpoints, addr, size))
+    }
+
+    pub fn write_register(&mut self, reg_index: usize, data: u32) {
         if reg_index == 0 {
             return;
         }
 
+        self.commit.prev_reg = self.registers[reg_index];
+        self.commit.reg_write = Some((reg_index, data));
         self.registers[reg_index] = data;
     }
 
@@ -51,17 +281,55 @@ impl Cpu {
     }
 
     fn write_csr(&mut self, csr_addr: u32, data: u32) -> Result<()> {
-        let csr = self.csrs.get_csr(csr_addr)?;
-        csr.write(csr_addr, &mut self.mem.vec, data)
+        match csr_addr {
+            MSTATUS => self.trap.set_mstatus(data),
+            MTVEC => self.trap.mtvec = data,
+            MEPC => self.trap.mepc = data,
+            MCAUSE => self.trap.mcause = data,
+            _ => {
+                let csr = self.csrs.get_csr(csr_addr)?;
+                csr.write(csr_addr, &mut self.mem, data)?;
+            }
+        }
+        Ok(())
     }
 
     fn read_csr(&mut self, csr_addr: u32) -> Result<u32> {
-        let csr = self.csrs.get_csr(csr_addr)?;
-        csr.read(csr_addr, &mut self.mem.vec)
+        Ok(match csr_addr {
+            MSTATUS => self.trap.mstatus(),
+            MTVEC => self.trap.mtvec,
+            MEPC => self.trap.mepc,
+            MCAUSE => self.trap.mcause,
+            _ => {
+                let csr = self.csrs.get_csr(csr_addr)?;
+                csr.read(csr_addr, &mut self.mem)?
+            }
+        })
+    }
+
+    /// Jump to the trap handler if an interrupt is pending and enabled.
+    fn take_interrupt(&mut self) {
+        if !self.trap.pending || !self.trap.enabled {
+            return;
+        }
+        self.trap.pending = false;
+        self.trap.mepc = self.pc;
+        self.trap.mcause = EXTERNAL_INTERRUPT;
+        self.trap.prev_enabled = true;
+        self.trap.enabled = false;
+        self.pc = self.trap.mtvec & !3;
     }
 
     pub fn tick(&mut self) -> Result<()> {
+        let (pc, trap) = (self.pc, self.trap);
+        self.take_interrupt();
         let insn = self.mem.read(self.pc, MemAccessSize::Word)?;
+        self.commit = Commit {
+            pc: self.pc,
+            insn,
+            interrupted: (self.pc != pc).then_some(pc),
+            ..Default::default()
+        };
         let opcode = insn & 0x7F;
         match opcode {
             0b0110111 => self.lui(insn.into()),
@@ -77,8 +345,43 @@ impl Cpu {
             _ => bail!("[invalid instruction] invalid opcode: {}", opcode),
         }
         self.insn_count += 1;
-        if self.insn_count % 512 == 0 {
+        if self.recent_pcs.len() == RECENT_PCS {
+            self.recent_pcs.pop_front();
+        }
+        self.recent_pcs.push_back(self.commit.pc);
+        if self.insn_count.is_multiple_of(512) {
             self.fps = self.fps_counter.tick() * 512;
+            self.shared_insn_count
+                .store(self.insn_count, Ordering::Relaxed);
+            if self.csrs.poll(&mut self.mem)? {
+                self.trap.pending = true;
+            }
+        }
+        if self.trap != trap {
+            self.commit.prev_trap = Some(trap);
+        }
+        if let Some((2, sp)) = self.commit.reg_write
+            && let Some(stack) = &mut self.stack
+        {
+            if (stack.start..=stack.end).contains(&sp) {
+                stack.entered = true;
+                stack.lowest = stack.lowest.min(sp);
+            } else if stack.entered {
+                bail!(
+                    "[stack] sp 0x{sp:08x} left the stack 0x{:08x}-0x{:08x}",
+                    stack.start,
+                    stack.end
+                );
+            }
+        }
+
+        if !self.hooks.is_empty() {
+            let mut hooks = std::mem::take(&mut self.hooks);
+            let result = hooks
+                .iter_mut()
+                .try_for_each(|hook| hook.retire(self, &self.commit));
+            self.hooks = hooks;
+            result?;
         }
         Ok(())
     }
@@ -127,6 +430,7 @@ impl Cpu {
                 insn.funct3
             ),
         };
+        self.commit.branch_taken = Some(do_branch);
         if do_branch {
             self.pc = self.pc.wrapping_add(insn.imm as u32);
         } else {
@@ -148,7 +452,25 @@ impl Cpu {
             ),
         };
         let addr = self.read_register(insn.rs1).wrapping_add(insn.imm as u32);
+        if self.mem.is_poisoned(addr, size) {
+            bail!("[heap] read of 0x{addr:08x} outside of live allocations");
+        }
+        if self.mem.is_uninitialized(addr, size) {
+            let message = format!(
+                "read of uninitialized memory at 0x{addr:08x}, pc: 0x{:08x}",
+                self.pc
+            );
+            if self.stop_on_uninitialized {
+                bail!("[memory] {message}");
+            }
+            let (_, count) = self.uninitialized_reads.entry(self.pc).or_insert_with(|| {
+                self.device_log.warn("memory", message);
+                (addr, 0)
+            });
+            *count += 1;
+        }
         let data = self.mem.read(addr, size)?;
+        self.commit.load = Some((addr, size));
         let data = match insn.funct3 {
             0b000 => data as i8 as i32 as u32,
             0b001 => data as i16 as i32 as u32,
@@ -171,7 +493,12 @@ impl Cpu {
                 insn.funct3
             ),
         };
+        if self.mem.is_poisoned(addr, size) {
+            bail!("[heap] write of 0x{addr:08x} outside of live allocations");
+        }
+        self.commit.prev_mem = self.mem.read(addr, size)?;
         self.mem.write(addr, size, data)?;
+        self.commit.store = Some((addr, size, data));
         self.pc += 4;
         Ok(())
     }
@@ -233,6 +560,12 @@ impl Cpu {
     }
 
     fn csr(&mut self, insn: IType) -> Result<()> {
+        if self.commit.insn == MRET {
+            self.pc = self.trap.mepc;
+            self.trap.enabled = self.trap.prev_enabled;
+            self.trap.prev_enabled = true;
+            return Ok(());
+        }
         let csr = insn.imm as u32 & 0xFFF;
         if insn.funct3 != 0b001 {
             bail!(
@@ -241,14 +574,25 @@ impl Cpu {
             );
         }
         let to_write = self.read_register(insn.rs1);
-        let data = if insn.rd != 0 { self.read_csr(csr)? } else { 0 };
+        let data = if insn.rd != 0 {
+            self.commit.csr_read = Some(csr);
+            self.read_csr(csr)?
+        } else {
+            0
+        };
         self.write_csr(csr, to_write)?;
+        self.commit.csr_write = Some((csr, to_write));
         self.write_register(insn.rd, data);
         self.pc += 4;
         Ok(())
     }
 }
 
+/// Whether a store of `size` bytes at `addr` overlaps any of `addrs`.
+pub fn stores_to(addrs: &HashSet<u32>, addr: u32, size: MemAccessSize) -> bool {
+    !addrs.is_empty() && (0..size as u32).any(|i| addrs.contains(&addr.wrapping_add(i)))
+}
+
 fn shamt(insn: IType) -> Result<(u32, bool)> {
     let shamt = insn.imm as u32 & 0b11111;
     let flag = match insn.imm as u32 >> 5 {
@@ -261,3 +605,32 @@ fn shamt(insn: IType) -> Result<(u32, bool)> {
     };
     Ok((shamt, flag))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uninitialized_reads() {
+        let mut cpu = Cpu::new(Csrs::new());
+        cpu.mem.track_initialized();
+        // lw t0, 0x100(x0); jal x0, -4
+        let program: Vec<u8> = [0x10002283u32, 0xffdff06f]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        cpu.flash(&program);
+
+        for _ in 0..10 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(
+            cpu.uninitialized_reads.iter().collect::<Vec<_>>(),
+            [(&0, &(0x100, 5))]
+        );
+        assert_eq!(cpu.device_log.lines().len(), 1);
+
+        cpu.stop_on_uninitialized = true;
+        assert!(cpu.tick().is_err());
+    }
+}
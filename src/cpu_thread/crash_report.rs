@@ -0,0 +1,60 @@
+use std::fmt::Write;
+
+use super::{
+    cpu::{ABI_NAMES, Cpu},
+    disassembler::disassemble,
+    memory::MemAccessSize,
+};
+
+/// Number of words dumped from the stack
+const STACK_WORDS: u32 = 32;
+
+/// Describe the state of a CPU that faulted with `err`, for showing to the user and bug tickets.
+pub fn crash_report(cpu: &Cpu, err: &anyhow::Error) -> String {
+    let mut report = String::new();
+    let r = &mut report;
+
+    writeln!(r, "CPU fault: {err:#}").unwrap();
+    writeln!(r, "pc: 0x{:08X}  {}", cpu.pc, disassemble_at(cpu, cpu.pc)).unwrap();
+    writeln!(r, "instructions retired: {}", cpu.insn_count).unwrap();
+
+    writeln!(r).unwrap();
+    writeln!(r, "Last executed instructions (oldest first):").unwrap();
+    for &pc in &cpu.recent_pcs {
+        writeln!(r, "  0x{pc:08X}  {}", disassemble_at(cpu, pc)).unwrap();
+    }
+
+    writeln!(r).unwrap();
+    writeln!(r, "Registers:").unwrap();
+    for row in (0..32).collect::<Vec<_>>().chunks(4) {
+        write!(r, " ").unwrap();
+        for &i in row {
+            write!(r, " {:>4} 0x{:08X}", ABI_NAMES[i], cpu.read_register(i)).unwrap();
+        }
+        writeln!(r).unwrap();
+    }
+
+    let sp = cpu.read_register(2);
+    writeln!(r).unwrap();
+    writeln!(r, "Stack (sp = 0x{sp:08X}):").unwrap();
+    for row in 0..STACK_WORDS / 4 {
+        let addr = sp.wrapping_add(row * 16);
+        write!(r, "  0x{addr:08X}:").unwrap();
+        for i in 0..4 {
+            match cpu.mem.read(addr.wrapping_add(i * 4), MemAccessSize::Word) {
+                Ok(word) => write!(r, " 0x{word:08X}").unwrap(),
+                Err(_) => write!(r, " ----------").unwrap(),
+            }
+        }
+        writeln!(r).unwrap();
+    }
+
+    report
+}
+
+fn disassemble_at(cpu: &Cpu, pc: u32) -> String {
+    match cpu.mem.read(pc, MemAccessSize::Word) {
+        Ok(insn) => disassemble(insn),
+        Err(_) => "<unmapped>".into(),
+    }
+}
@@ -0,0 +1,216 @@
+use super::{
+    cpu::ABI_NAMES,
+    instruction_formats::{BType, IType, JType, RType, SType, UType},
+};
+
+/// Disassemble an instruction in the style of Spike's disassembler.
+///
+/// Branch and jump targets are printed relative to the pc.
+pub fn disassemble(insn: u32) -> String {
+    let opcode = insn & 0x7F;
+    match opcode {
+        0b0110111 => {
+            let i = UType::from(insn);
+            op("lui", &[reg(i.rd), format!("0x{:x}", i.imm >> 12)])
+        }
+        0b0010111 => {
+            let i = UType::from(insn);
+            op("auipc", &[reg(i.rd), format!("0x{:x}", i.imm >> 12)])
+        }
+        0b1101111 => {
+            let i = JType::from(insn);
+            match i.rd {
+                0 => op("j", &[offset(i.imm)]),
+                1 => op("jal", &[offset(i.imm)]),
+                _ => op("jal", &[reg(i.rd), offset(i.imm)]),
+            }
+        }
+        0b1100111 => {
+            let i = IType::from(insn);
+            match (i.rd, i.rs1, i.imm) {
+                (0, 1, 0) => "ret".into(),
+                (0, _, 0) => op("jr", &[reg(i.rs1)]),
+                (1, _, 0) => op("jalr", &[reg(i.rs1)]),
+                _ => op("jalr", &[reg(i.rd), format!("{}({})", i.imm, reg(i.rs1))]),
+            }
+        }
+        0b1100011 => {
+            let i = BType::from(insn);
+            let name = match i.funct3 {
+                0b000 => "beq",
+                0b001 => "bne",
+                0b100 => "blt",
+                0b101 => "bge",
+                0b110 => "bltu",
+                0b111 => "bgeu",
+                _ => return unknown(),
+            };
+            match (name, i.rs2) {
+                ("beq", 0) => op("beqz", &[reg(i.rs1), offset(i.imm)]),
+                ("bne", 0) => op("bnez", &[reg(i.rs1), offset(i.imm)]),
+                _ => op(name, &[reg(i.rs1), reg(i.rs2), offset(i.imm)]),
+            }
+        }
+        0b0000011 => {
+            let i = IType::from(insn);
+            let name = match i.funct3 {
+                0b000 => "lb",
+                0b001 => "lh",
+                0b010 => "lw",
+                0b100 => "lbu",
+                0b101 => "lhu",
+                _ => return unknown(),
+            };
+            op(name, &[reg(i.rd), format!("{}({})", i.imm, reg(i.rs1))])
+        }
+        0b0100011 => {
+            let i = SType::from(insn);
+            let name = match i.funct3 {
+                0b000 => "sb",
+                0b001 => "sh",
+                0b010 => "sw",
+                _ => return unknown(),
+            };
+            op(name, &[reg(i.rs2), format!("{}({})", i.imm, reg(i.rs1))])
+        }
+        0b0010011 => {
+            let i = IType::from(insn);
+            let shamt = i.imm & 0b11111;
+            match (i.funct3, i.rd, i.rs1, i.imm) {
+                (0b000, 0, 0, 0) => "nop".into(),
+                (0b000, _, 0, _) => op("li", &[reg(i.rd), i.imm.to_string()]),
+                (0b000, _, _, 0) => op("mv", &[reg(i.rd), reg(i.rs1)]),
+                (0b000, ..) => op("addi", &[reg(i.rd), reg(i.rs1), i.imm.to_string()]),
+                (0b010, ..) => op("slti", &[reg(i.rd), reg(i.rs1), i.imm.to_string()]),
+                (0b011, _, _, 1) => op("seqz", &[reg(i.rd), reg(i.rs1)]),
+                (0b011, ..) => op("sltiu", &[reg(i.rd), reg(i.rs1), i.imm.to_string()]),
+                (0b100, _, _, -1) => op("not", &[reg(i.rd), reg(i.rs1)]),
+                (0b100, ..) => op("xori", &[reg(i.rd), reg(i.rs1), i.imm.to_string()]),
+                (0b110, ..) => op("ori", &[reg(i.rd), reg(i.rs1), i.imm.to_string()]),
+                (0b111, ..) => op("andi", &[reg(i.rd), reg(i.rs1), i.imm.to_string()]),
+                (0b001, ..) => op("slli", &[reg(i.rd), reg(i.rs1), shamt.to_string()]),
+                (0b101, ..) if i.imm >> 5 == 0 => {
+                    op("srli", &[reg(i.rd), reg(i.rs1), shamt.to_string()])
+                }
+                (0b101, ..) => op("srai", &[reg(i.rd), reg(i.rs1), shamt.to_string()]),
+                _ => unknown(),
+            }
+        }
+        0b0110011 => {
+            let i = RType::from(insn);
+            let name = match (i.funct3, i.funct7) {
+                (0b000, 0) => "add",
+                (0b000, 0b100000) if i.rs1 == 0 => return op("neg", &[reg(i.rd), reg(i.rs2)]),
+                (0b000, 0b100000) => "sub",
+                (0b001, 0) => "sll",
+                (0b010, 0) => "slt",
+                (0b011, 0) if i.rs1 == 0 => return op("snez", &[reg(i.rd), reg(i.rs2)]),
+                (0b011, 0) => "sltu",
+                (0b100, 0) => "xor",
+                (0b101, 0) => "srl",
+                (0b101, 0b100000) => "sra",
+                (0b110, 0) => "or",
+                (0b111, 0) => "and",
+                _ => return unknown(),
+            };
+            op(name, &[reg(i.rd), reg(i.rs1), reg(i.rs2)])
+        }
+        0b1110011 => {
+            if insn == 0x30200073 {
+                return "mret".into();
+            }
+            let i = IType::from(insn);
+            if i.funct3 != 0b001 {
+                return unknown();
+            }
+            let csr = format!("0x{:x}", i.imm as u32 & 0xFFF);
+            match i.rd {
+                0 => op("csrw", &[csr, reg(i.rs1)]),
+                _ => op("csrrw", &[reg(i.rd), csr, reg(i.rs1)]),
+            }
+        }
+        _ => unknown(),
+    }
+}
+
+fn op(name: &str, args: &[String]) -> String {
+    format!("{:<7} {}", name, args.join(", "))
+}
+
+fn reg(idx: usize) -> String {
+    ABI_NAMES[idx].into()
+}
+
+fn offset(imm: i32) -> String {
+    if imm < 0 {
+        format!("pc - {}", -imm)
+    } else {
+        format!("pc + {}", imm)
+    }
+}
+
+fn unknown() -> String {
+    "unknown".into()
+}
+
+/// Base mnemonic of an instruction, pseudo-instructions are not used.
+pub fn mnemonic(insn: u32) -> &'static str {
+    let funct3 = (insn >> 12) & 0x7;
+    let funct7 = insn >> 25;
+    match insn & 0x7F {
+        0b0110111 => "lui",
+        0b0010111 => "auipc",
+        0b1101111 => "jal",
+        0b1100111 => "jalr",
+        0b1100011 => match funct3 {
+            0b000 => "beq",
+            0b001 => "bne",
+            0b100 => "blt",
+            0b101 => "bge",
+            0b110 => "bltu",
+            0b111 => "bgeu",
+            _ => "unknown",
+        },
+        0b0000011 => match funct3 {
+            0b000 => "lb",
+            0b001 => "lh",
+            0b010 => "lw",
+            0b100 => "lbu",
+            0b101 => "lhu",
+            _ => "unknown",
+        },
+        0b0100011 => match funct3 {
+            0b000 => "sb",
+            0b001 => "sh",
+            0b010 => "sw",
+            _ => "unknown",
+        },
+        0b0010011 => match funct3 {
+            0b000 => "addi",
+            0b001 => "slli",
+            0b010 => "slti",
+            0b011 => "sltiu",
+            0b100 => "xori",
+            0b101 if funct7 == 0 => "srli",
+            0b101 => "srai",
+            0b110 => "ori",
+            _ => "andi",
+        },
+        0b0110011 => match (funct3, funct7) {
+            (0b000, 0) => "add",
+            (0b000, 0b100000) => "sub",
+            (0b001, 0) => "sll",
+            (0b010, 0) => "slt",
+            (0b011, 0) => "sltu",
+            (0b100, 0) => "xor",
+            (0b101, 0) => "srl",
+            (0b101, 0b100000) => "sra",
+            (0b110, 0) => "or",
+            (0b111, 0) => "and",
+            _ => "unknown",
+        },
+        0b1110011 if insn == 0x30200073 => "mret",
+        0b1110011 if funct3 == 0b001 => "csrrw",
+        _ => "unknown",
+    }
+}
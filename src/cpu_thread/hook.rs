@@ -0,0 +1,68 @@
+use std::any::Any;
+
+use anyhow::Result;
+
+use super::{
+    cpu::{Cpu, Trap},
+    memory::MemAccessSize,
+};
+
+/// Effects of the last retired instruction
+#[derive(Default, Clone, Copy)]
+pub struct Commit {
+    pub pc: u32,
+    pub insn: u32,
+    /// Register written and its new value, writes to `x0` are not recorded
+    pub reg_write: Option<(usize, u32)>,
+    /// Value of the written register before the write
+    pub prev_reg: u32,
+    /// Address and size of a load
+    pub load: Option<(u32, MemAccessSize)>,
+    /// Address, size and value of a store
+    pub store: Option<(u32, MemAccessSize, u32)>,
+    /// Memory contents at the store address before the store
+    pub prev_mem: u32,
+    /// Address of a csr that was read
+    pub csr_read: Option<u32>,
+    /// Csr address and the value written to it
+    pub csr_write: Option<(u32, u32)>,
+    /// Whether a conditional branch was taken
+    pub branch_taken: Option<bool>,
+    /// Pc interrupted by an interrupt taken right before this instruction, `pc` is the
+    /// first instruction of the handler then
+    pub interrupted: Option<u32>,
+    /// Trap state before the instruction, if it or an interrupt changed it
+    pub prev_trap: Option<Trap>,
+}
+
+impl Commit {
+    /// Whether this was a `jal`/`jalr` that links to `ra` or `t0`.
+    pub fn is_call(&self) -> bool {
+        let opcode = self.insn & 0x7F;
+        let rd = (self.insn >> 7) & 0x1F;
+        (opcode == 0b1101111 || opcode == 0b1100111) && (rd == 1 || rd == 5)
+    }
+
+    /// Whether this was a `jalr` through `ra` or `t0` that doesn't link.
+    pub fn is_return(&self) -> bool {
+        let rd = (self.insn >> 7) & 0x1F;
+        let rs1 = (self.insn >> 15) & 0x1F;
+        self.insn & 0x7F == 0b1100111 && rd == 0 && (rs1 == 1 || rs1 == 5)
+    }
+}
+
+/// Observer called by the CPU after every retired instruction
+pub trait Hook: Any + Send {
+    fn retire(&mut self, cpu: &Cpu, commit: &Commit) -> Result<()>;
+
+    /// Called when the CPU is reset.
+    fn reset(&mut self) {}
+
+    /// Called when the GUI requests an update, and when the CPU stops.
+    fn publish(&mut self) {}
+
+    /// Called when the emulator exits, e.g. to write reports.
+    fn finish(&mut self, _cpu: &Cpu) -> Result<()> {
+        Ok(())
+    }
+}
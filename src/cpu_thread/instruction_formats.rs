@@ -110,7 +110,7 @@ pub struct UType {
 impl From<u32> for UType {
     fn from(insn: u32) -> Self {
         UType {
-            imm: (insn & 0xffff_f000) as u32,
+            imm: insn & 0xffff_f000,
             rd: ((insn >> 7) & 0x1f) as usize,
         }
     }
@@ -1,5 +1,9 @@
+use std::ops::Range;
+
 use anyhow::{Result, bail};
 
+use crate::savestate::{StateReader, StateWriter};
+
 /// The different sizes used for memory accesses
 #[derive(Clone, Copy)]
 #[repr(usize)]
@@ -14,13 +18,30 @@ pub enum MemAccessSize {
 
 pub struct Memory {
     pub vec: Vec<u8>,
+    /// Bytes that must not be accessed, e.g. freed heap memory.
+    /// Only allocated once the guest reports heap usage.
+    poisoned: Option<Bitmap>,
+    /// Whether accesses to poisoned bytes are reported, the allocator turns this off
+    /// while it touches its own bookkeeping
+    pub checks: bool,
+    /// Bytes that were flashed or written, only tracked when enabled
+    initialized: Option<Bitmap>,
+    /// Value RAM is filled with on reset, a pattern other than zero shakes out
+    /// reads of uninitialized memory
+    pub fill: u8,
 }
 
 impl Memory {
     pub fn new(size: usize) -> Self {
         assert_eq!(size % 4, 0);
 
-        Self { vec: vec![0; size] }
+        Self {
+            vec: vec![0; size],
+            poisoned: None,
+            checks: true,
+            initialized: None,
+            fill: 0,
+        }
     }
 
     pub fn read(&self, addr: u32, osize: MemAccessSize) -> Result<u32> {
@@ -60,11 +81,223 @@ impl Memory {
 
         let data = data.to_le_bytes();
         self.vec[addr..addr + size].copy_from_slice(&data[..size]);
+        if let Some(map) = &mut self.initialized {
+            map.set(addr..addr + size, true);
+        }
 
         Ok(())
     }
 
     pub fn flash(&mut self, data: &[u8]) {
         self.vec[..data.len()].copy_from_slice(data);
+        if let Some(map) = &mut self.initialized {
+            map.set(0..data.len(), true);
+        }
+    }
+
+    /// Fill RAM with [`Memory::fill`] and forget which bytes were written.
+    pub fn clear(&mut self) {
+        self.vec.fill(self.fill);
+        if let Some(map) = &mut self.initialized {
+            map.set(0..map.len, false);
+        }
+    }
+
+    /// Start tracking which bytes were written, so loads of other bytes can be reported.
+    pub fn track_initialized(&mut self) {
+        self.initialized = Some(Bitmap::new(self.vec.len(), false));
+    }
+
+    /// Whether an access of `size` bytes at `addr` reads bytes that were never written.
+    pub fn is_uninitialized(&self, addr: u32, size: MemAccessSize) -> bool {
+        let addr = addr as usize;
+        self.initialized
+            .as_ref()
+            .is_some_and(|map| map.any(addr..addr + size as usize, false))
+    }
+
+    /// Mark `len` bytes from `addr` as poisoned or accessible, clamped to the memory size.
+    pub fn poison(&mut self, addr: u32, len: u32, poisoned: bool) {
+        let size = self.vec.len();
+        let map = self
+            .poisoned
+            .get_or_insert_with(|| Bitmap::new(size, false));
+        let start = (addr as usize).min(size);
+        let end = (addr as usize).saturating_add(len as usize).min(size);
+        map.set(start..end, poisoned);
+    }
+
+    /// Whether an access of `size` bytes at `addr` touches poisoned memory.
+    pub fn is_poisoned(&self, addr: u32, size: MemAccessSize) -> bool {
+        let Some(map) = &self.poisoned else {
+            return false;
+        };
+        let addr = addr as usize;
+        self.checks && map.any(addr..addr + size as usize, true)
+    }
+
+    /// Forget all poisoned bytes.
+    pub fn clear_poison(&mut self) {
+        self.poisoned = None;
+        self.checks = true;
+    }
+
+    /// Save the poisoned and initialized bitmaps, each map is empty if it isn't used.
+    pub fn save_shadow(&self, w: &mut StateWriter) {
+        save_map(w, &self.poisoned);
+        w.u32(self.checks as u32);
+        save_map(w, &self.initialized);
+    }
+
+    /// Read the maps written by [`Memory::save_shadow`], without applying them yet.
+    pub fn read_shadow(&self, r: &mut StateReader) -> Result<Shadow> {
+        Ok(Shadow {
+            poisoned: load_map(r, self.vec.len())?,
+            checks: r.u32()? != 0,
+            initialized: load_map(r, self.vec.len())?,
+        })
+    }
+
+    pub fn load_shadow(&mut self, shadow: Shadow) {
+        self.poisoned = shadow.poisoned;
+        self.checks = shadow.checks;
+        // Keep tracking if it is enabled, even for states saved without it
+        if self.initialized.is_some() {
+            self.initialized = Some(
+                shadow
+                    .initialized
+                    .unwrap_or_else(|| Bitmap::new(self.vec.len(), true)),
+            );
+        }
+    }
+}
+
+/// Shadow memory read from a save state
+pub struct Shadow {
+    poisoned: Option<Bitmap>,
+    checks: bool,
+    initialized: Option<Bitmap>,
+}
+
+/// One bit per byte of RAM
+struct Bitmap {
+    words: Vec<u64>,
+    /// Number of bits
+    len: usize,
+}
+
+impl Bitmap {
+    fn new(len: usize, value: bool) -> Self {
+        let word = if value { u64::MAX } else { 0 };
+        Self {
+            words: vec![word; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    /// Calls `f` with the index and mask of every word covering `range`.
+    fn for_words(range: Range<usize>, mut f: impl FnMut(usize, u64) -> bool) {
+        let mut bit = range.start;
+        while bit < range.end {
+            let shift = bit % 64;
+            let count = (64 - shift).min(range.end - bit);
+            if !f(bit / 64, (u64::MAX >> (64 - count)) << shift) {
+                return;
+            }
+            bit += count;
+        }
+    }
+
+    fn set(&mut self, range: Range<usize>, value: bool) {
+        Self::for_words(range, |i, mask| {
+            if value {
+                self.words[i] |= mask;
+            } else {
+                self.words[i] &= !mask;
+            }
+            true
+        });
+    }
+
+    /// Whether any bit in `range` is `value`, false if `range` is out of bounds.
+    fn any(&self, range: Range<usize>, value: bool) -> bool {
+        if range.end > self.len {
+            return false;
+        }
+        let mut found = false;
+        Self::for_words(range, |i, mask| {
+            let word = if value { self.words[i] } else { !self.words[i] };
+            found = word & mask != 0;
+            !found
+        });
+        found
+    }
+}
+
+fn save_map(w: &mut StateWriter, map: &Option<Bitmap>) {
+    let words = map.as_ref().map_or(&[][..], |map| &map.words);
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    w.bytes(&bytes);
+}
+
+fn load_map(r: &mut StateReader, len: usize) -> Result<Option<Bitmap>> {
+    let bytes = r.bytes()?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    if bytes.len() != len.div_ceil(64) * 8 {
+        bail!("[savestate] shadow memory size mismatch");
+    }
+    let words = bytes
+        .chunks_exact(8)
+        .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+    Ok(Some(Bitmap { words, len }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmap() {
+        let mut map = Bitmap::new(200, false);
+        map.set(60..130, true);
+        assert!(!map.any(0..60, true));
+        assert!(map.any(59..61, true));
+        assert!(map.any(129..130, true));
+        assert!(!map.any(130..200, true));
+        assert!(!map.any(60..130, false));
+        assert!(map.any(60..131, false));
+        assert!(!map.any(199..201, false));
+
+        map.set(64..128, false);
+        assert!(map.any(60..64, true) && map.any(128..130, true));
+        assert!(!map.any(64..128, true));
+    }
+
+    #[test]
+    fn initialized_bytes() {
+        let mut mem = Memory::new(256);
+        mem.track_initialized();
+        mem.flash(&[1; 10]);
+        mem.write(100, MemAccessSize::HalfWord, 0).unwrap();
+        assert!(!mem.is_uninitialized(6, MemAccessSize::Word));
+        assert!(mem.is_uninitialized(8, MemAccessSize::Word));
+        assert!(!mem.is_uninitialized(100, MemAccessSize::HalfWord));
+        assert!(mem.is_uninitialized(100, MemAccessSize::Word));
+
+        let mut w = StateWriter::new();
+        mem.save_shadow(&mut w);
+        let data = w.into_inner();
+        // An empty poison map, the checks flag and 4 words of initialized bits
+        assert_eq!(data.len(), 4 + 4 + 4 + 4 * 8);
+
+        mem.clear();
+        assert!(mem.is_uninitialized(0, MemAccessSize::Byte));
+        let shadow = mem.read_shadow(&mut StateReader::new(&data)).unwrap();
+        mem.load_shadow(shadow);
+        assert!(!mem.is_uninitialized(100, MemAccessSize::HalfWord));
+        assert!(mem.is_uninitialized(102, MemAccessSize::Byte));
     }
 }
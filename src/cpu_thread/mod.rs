@@ -1,6 +1,15 @@
+pub mod coverage;
 pub mod cpu;
+pub mod crash_report;
+pub mod disassembler;
+pub mod hook;
 mod instruction_formats;
-mod memory;
+pub mod memory;
+pub mod profiler;
+pub mod reverse;
+pub mod source;
+pub mod stats;
+pub mod trace;
 
 use std::{
     sync::{
@@ -12,7 +21,7 @@ use std::{
 
 use anyhow::Result;
 
-use crate::cpu_thread::cpu::Cpu;
+use crate::cpu_thread::cpu::{Cpu, Stack};
 
 #[derive(Default, Clone, Copy)]
 pub struct CpuState {
@@ -20,6 +29,7 @@ pub struct CpuState {
     pub pc: u32,
     pub insn_count: u64,
     pub fps: usize,
+    pub stack: Option<Stack>,
 }
 
 impl CpuState {
@@ -29,6 +39,7 @@ impl CpuState {
             pc: 0,
             insn_count: 0,
             fps: 0,
+            stack: None,
         }
     }
 }
@@ -85,7 +96,8 @@ impl CpuHandle {
 
         self.stop_thread.store(true, Ordering::Relaxed);
 
-        let (cpu, result) = thread_handle.join().unwrap();
+        let (mut cpu, result) = thread_handle.join().unwrap();
+        cpu.publish();
         self.stopped_cpu = Some(cpu);
 
         self.stop_thread.store(false, Ordering::Relaxed);
@@ -93,6 +105,49 @@ impl CpuHandle {
         result
     }
 
+    /// Whether the CPU thread is currently running.
+    pub fn is_running(&self) -> bool {
+        self.thread_handle
+            .as_ref()
+            .is_some_and(|thread_handle| !thread_handle.is_finished())
+    }
+
+    /// Join the CPU thread if it stopped on its own (breakpoint or error) and return its result.
+    pub fn reap(&mut self) -> Option<Result<()>> {
+        if !self.thread_handle.as_ref()?.is_finished() {
+            return None;
+        }
+        Some(self.stop())
+    }
+
+    /// Run `f` on the CPU, pausing the thread for the duration if it is running.
+    pub fn with_cpu<R>(&mut self, f: impl FnOnce(&mut Cpu) -> R) -> Result<R> {
+        let running = self.is_running();
+        self.stop()?;
+        let cpu = self.stopped_cpu.as_mut().unwrap();
+        let result = f(cpu);
+        cpu.publish();
+        if running {
+            self.start();
+        }
+        Ok(result)
+    }
+
+    /// The CPU, if the thread is stopped.
+    pub fn stopped_cpu(&mut self) -> Option<&mut Cpu> {
+        self.stopped_cpu.as_mut()
+    }
+
+    /// Execute a single instruction on the stopped CPU.
+    pub fn step(&mut self) -> Result<()> {
+        let Some(cpu) = &mut self.stopped_cpu else {
+            return Ok(());
+        };
+        let result = cpu.tick();
+        cpu.publish();
+        result
+    }
+
     pub fn request_stop(&self) {
         self.stop_thread.store(true, Ordering::Relaxed);
     }
@@ -119,17 +174,31 @@ fn run_thread(
     std::thread::Builder::new()
         .name("cpu".into())
         .spawn(move || {
+            // Don't stop at the breakpoint we are continuing from
+            let mut first = true;
             loop {
                 if stop_thread.load(Ordering::Relaxed) {
                     return (cpu, Ok(()));
                 }
 
+                if !first && cpu.breakpoints.contains(&cpu.pc) {
+                    *cpu_state.lock().unwrap() = make_state(&cpu);
+                    return (cpu, Ok(()));
+                }
+                first = false;
+
                 if let Err(err) = cpu.tick() {
                     return (cpu, Err(err));
                 }
 
+                if cpu.hit_watchpoint() {
+                    *cpu_state.lock().unwrap() = make_state(&cpu);
+                    return (cpu, Ok(()));
+                }
+
                 if request_update.swap(false, Ordering::Relaxed) {
                     *cpu_state.lock().unwrap() = make_state(&cpu);
+                    cpu.publish();
                 }
                 std::thread::yield_now();
             }
@@ -143,5 +212,6 @@ fn make_state(cpu: &Cpu) -> CpuState {
         pc: cpu.pc,
         insn_count: cpu.insn_count,
         fps: cpu.fps,
+        stack: cpu.stack,
     }
 }
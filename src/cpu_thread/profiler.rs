@@ -0,0 +1,240 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+
+use super::{
+    cpu::Cpu,
+    hook::{Commit, Hook},
+};
+use crate::elf::Symbols;
+
+/// Deeper call stacks are cut off, e.g. after unbalanced calls and returns
+const MAX_DEPTH: usize = 256;
+/// Number of instructions listed in the report
+const HOTTEST: usize = 20;
+
+/// A call stack, stored as a function entry address and the stack of its caller
+struct Frame {
+    caller: Option<usize>,
+    addr: u32,
+    depth: usize,
+    /// Executed instructions with exactly this call stack
+    count: u64,
+}
+
+/// Counts executed instructions per pc and per call stack.
+///
+/// Call stacks are tracked from `jal`/`jalr` instructions that link to `ra` or `t0`,
+/// and returns through them.
+pub struct Profiler {
+    /// Reports are written to `<prefix>.txt` and `<prefix>.folded`
+    pub prefix: PathBuf,
+    /// Executed instructions per pc
+    pc_counts: HashMap<u32, u64>,
+    /// Every call stack seen so far, indexed by `callees`
+    frames: Vec<Frame>,
+    /// Index of the stack of each caller and called address
+    callees: HashMap<(Option<usize>, u32), usize>,
+    /// The current call stack, `None` before the first instruction
+    current: Option<usize>,
+    /// Instructions executed since the stack last changed
+    pending: u64,
+}
+
+impl Profiler {
+    pub fn new(prefix: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            pc_counts: HashMap::new(),
+            frames: vec![],
+            callees: HashMap::new(),
+            current: None,
+            pending: 0,
+        }
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some(current) = self.current {
+            self.frames[current].count += self.pending;
+        }
+        self.pending = 0;
+    }
+
+    fn call(&mut self, addr: u32) {
+        let depth = self.current.map_or(0, |current| self.frames[current].depth);
+        if depth == MAX_DEPTH {
+            return;
+        }
+        let frames = &mut self.frames;
+        let caller = self.current;
+        let callee = *self.callees.entry((caller, addr)).or_insert_with(|| {
+            frames.push(Frame {
+                caller,
+                addr,
+                depth: depth + 1,
+                count: 0,
+            });
+            frames.len() - 1
+        });
+        self.current = Some(callee);
+    }
+
+    /// Entry addresses of the called functions, outermost first.
+    fn stack(&self, mut frame: usize) -> Vec<u32> {
+        let mut stack = vec![self.frames[frame].addr];
+        while let Some(caller) = self.frames[frame].caller {
+            stack.push(self.frames[caller].addr);
+            frame = caller;
+        }
+        stack.reverse();
+        stack
+    }
+
+    /// Call stacks that executed instructions, with their counts.
+    fn stacks(&self) -> impl Iterator<Item = (Vec<u32>, u64)> + '_ {
+        self.frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| frame.count != 0)
+            .map(|(i, frame)| (self.stack(i), frame.count))
+    }
+
+    /// Write the per-function report and the folded stacks for flamegraph tools.
+    pub fn write(&mut self, symbols: &Symbols) -> Result<()> {
+        self.flush_pending();
+
+        let report_path = self.prefix.with_extension("txt");
+        std::fs::write(&report_path, self.report(symbols))
+            .with_context(|| format!("failed to write {}", report_path.display()))?;
+
+        let mut folded = String::new();
+        for (stack, count) in self.stacks() {
+            let names: Vec<_> = stack.iter().map(|&addr| symbols.format(addr)).collect();
+            writeln!(folded, "{} {}", names.join(";"), count).unwrap();
+        }
+        let folded_path = self.prefix.with_extension("folded");
+        std::fs::write(&folded_path, folded)
+            .with_context(|| format!("failed to write {}", folded_path.display()))
+    }
+
+    fn report(&self, symbols: &Symbols) -> String {
+        let total: u64 = self.pc_counts.values().sum();
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+
+        // (self, inclusive) per function
+        let mut functions: HashMap<String, (u64, u64)> = HashMap::new();
+        for (stack, count) in self.stacks() {
+            let names: HashSet<_> = stack.iter().map(|&addr| symbols.format(addr)).collect();
+            for name in names {
+                functions.entry(name).or_default().1 += count;
+            }
+            if let Some(&top) = stack.last() {
+                functions.entry(symbols.format(top)).or_default().0 += count;
+            }
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut r = String::new();
+        writeln!(r, "{total} instructions").unwrap();
+        writeln!(r).unwrap();
+        writeln!(
+            r,
+            "{:>8} {:>12} {:>8} {:>12}  function",
+            "self%", "self", "incl%", "incl"
+        )
+        .unwrap();
+        for (name, (self_count, incl_count)) in functions {
+            writeln!(
+                r,
+                "{:>7.2}% {:>12} {:>7.2}% {:>12}  {}",
+                percent(self_count),
+                self_count,
+                percent(incl_count),
+                incl_count,
+                name
+            )
+            .unwrap();
+        }
+
+        let mut pcs: Vec<_> = self.pc_counts.iter().collect();
+        pcs.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        writeln!(r).unwrap();
+        writeln!(r, "Hottest instructions:").unwrap();
+        for (&pc, &count) in pcs.into_iter().take(HOTTEST) {
+            writeln!(
+                r,
+                "{:>7.2}% {:>12}  0x{:08x}  {}",
+                percent(count),
+                count,
+                pc,
+                symbols.format(pc)
+            )
+            .unwrap();
+        }
+        r
+    }
+}
+
+impl Hook for Profiler {
+    fn retire(&mut self, cpu: &Cpu, commit: &Commit) -> Result<()> {
+        if self.current.is_none() {
+            self.call(commit.pc);
+        }
+        *self.pc_counts.entry(commit.pc).or_default() += 1;
+        self.pending += 1;
+
+        if commit.is_call() {
+            self.flush_pending();
+            self.call(cpu.pc);
+        } else if commit.is_return() {
+            self.flush_pending();
+            if let Some(caller) = self.current.and_then(|current| self.frames[current].caller) {
+                self.current = Some(caller);
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.flush_pending();
+        self.current = None;
+    }
+
+    fn finish(&mut self, cpu: &Cpu) -> Result<()> {
+        self.write(&cpu.symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csrs::Csrs;
+
+    #[test]
+    fn call_stacks() {
+        let mut cpu = Cpu::new(Csrs::new());
+        // jal ra, 8; jal x0, -4; addi t0, t0, 1; jalr x0, 0(ra)
+        for (i, word) in [0x008000efu32, 0xffdff06f, 0x00128293, 0x00008067]
+            .iter()
+            .enumerate()
+        {
+            cpu.mem.vec[i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+        }
+        cpu.add_hook(Box::new(Profiler::new("unused")));
+        for _ in 0..100 {
+            cpu.tick().unwrap();
+        }
+
+        let profiler = cpu.hook_mut::<Profiler>().unwrap();
+        profiler.flush_pending();
+        assert_eq!(profiler.frames.len(), 2);
+        let mut stacks: Vec<_> = profiler.stacks().collect();
+        stacks.sort();
+        assert_eq!(stacks, [(vec![0], 50), (vec![0, 8], 50)]);
+    }
+}
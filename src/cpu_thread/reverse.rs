@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+
+use anyhow::{Context, Result};
+
+use super::{
+    cpu::{Cpu, Trap, stores_to},
+    hook::{Commit, Hook},
+    memory::MemAccessSize,
+};
+
+/// Memory used for recording when no budget is given
+pub const DEFAULT_RECORD_BUDGET: usize = 64 * 1024 * 1024;
+
+/// Convert a recording budget given in MiB to bytes.
+pub fn budget_from_mib(mib: usize) -> Result<usize> {
+    mib.checked_mul(1024 * 1024)
+        .context("[reverse] recording budget is too large")
+}
+
+/// State changed by one retired instruction, enough to undo it
+struct Delta {
+    /// Pc before the instruction, or before the interrupt taken right before it
+    pc: u32,
+    /// Register written and its previous value
+    reg: Option<(u8, u32)>,
+    /// Store address, size and previous memory contents
+    store: Option<(u32, MemAccessSize, u32)>,
+    /// Csr address and the value written, devices can't be rewound so this is informational
+    csr_write: Option<(u32, u32)>,
+    /// Trap state before an interrupt, `mret` or trap csr write, boxed as it is rare
+    trap: Option<Box<Trap>>,
+}
+
+impl Delta {
+    /// Memory used by the delta, counted against the recording budget
+    fn size(&self) -> usize {
+        size_of::<Delta>() + self.trap.as_ref().map_or(0, |_| size_of::<Trap>())
+    }
+}
+
+/// Records the effects of every retired instruction so execution can be stepped backwards.
+///
+/// Only CPU registers, trap state and memory are rewound, side effects on devices stay.
+pub struct Recorder {
+    deltas: VecDeque<Delta>,
+    /// Bytes the recording may use
+    budget: usize,
+    /// Bytes used by `deltas`
+    used: usize,
+}
+
+impl Recorder {
+    /// Create a recorder using at most `budget` bytes of memory, dropping the oldest
+    /// instructions when it is used up.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            deltas: VecDeque::new(),
+            budget,
+            used: 0,
+        }
+    }
+
+    /// Number of instructions that can be stepped back.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Csr writes of the recorded instructions, most recent last.
+    pub fn csr_writes(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.deltas.iter().filter_map(|delta| delta.csr_write)
+    }
+
+    fn push(&mut self, delta: Delta) {
+        let size = delta.size();
+        while self.used + size > self.budget {
+            let Some(oldest) = self.deltas.pop_front() else {
+                break;
+            };
+            self.used -= oldest.size();
+        }
+        if self.deltas.len() == self.deltas.capacity() {
+            // Grow like a Vec would, but never past what the budget can hold
+            let room = (self.budget / size_of::<Delta>()).saturating_sub(self.deltas.len());
+            self.deltas
+                .reserve_exact(self.deltas.len().clamp(1, room.max(1)));
+        }
+        self.used += size;
+        self.deltas.push_back(delta);
+    }
+
+    fn pop(&mut self) -> Option<Delta> {
+        let delta = self.deltas.pop_back()?;
+        self.used -= delta.size();
+        Some(delta)
+    }
+}
+
+impl Hook for Recorder {
+    fn retire(&mut self, _cpu: &Cpu, commit: &Commit) -> Result<()> {
+        self.push(Delta {
+            pc: commit.interrupted.unwrap_or(commit.pc),
+            reg: commit
+                .reg_write
+                .map(|(reg, _)| (reg as u8, commit.prev_reg)),
+            store: commit
+                .store
+                .map(|(addr, size, _)| (addr, size, commit.prev_mem)),
+            csr_write: commit.csr_write,
+            trap: commit.prev_trap.map(Box::new),
+        });
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.deltas.clear();
+        self.used = 0;
+    }
+}
+
+/// Undo the last recorded instruction, returns `false` if there is nothing left to undo.
+pub fn step_back(cpu: &mut Cpu) -> Result<bool> {
+    let Some(delta) = pop(cpu)? else {
+        return Ok(false);
+    };
+    undo(cpu, &delta)?;
+    Ok(true)
+}
+
+/// Step back until a breakpoint, or an instruction that stored to a watchpoint.
+///
+/// Returns `false` if the recording ran out first.
+pub fn reverse_continue(cpu: &mut Cpu) -> Result<bool> {
+    while let Some(delta) = pop(cpu)? {
+        let hit_watchpoint = delta
+            .store
+            .is_some_and(|(addr, size, _)| stores_to(&cpu.watchpoints, addr, size));
+        undo(cpu, &delta)?;
+        if hit_watchpoint || cpu.breakpoints.contains(&cpu.pc) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn pop(cpu: &mut Cpu) -> Result<Option<Delta>> {
+    let recorder = cpu
+        .hook_mut::<Recorder>()
+        .context("recording is off, use record on")?;
+    Ok(recorder.pop())
+}
+
+fn undo(cpu: &mut Cpu, delta: &Delta) -> Result<()> {
+    if let Some((addr, size, value)) = delta.store {
+        cpu.mem.write(addr, size, value)?;
+    }
+    if let Some((reg, value)) = delta.reg {
+        cpu.registers[reg as usize] = value;
+    }
+    if let Some(trap) = &delta.trap {
+        cpu.trap = **trap;
+    }
+    cpu.pc = delta.pc;
+    cpu.insn_count -= 1;
+    cpu.recent_pcs.pop_back();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csrs::Csrs;
+
+    fn write_words(cpu: &mut Cpu, addr: usize, words: &[u32]) {
+        for (i, word) in words.iter().enumerate() {
+            cpu.mem.vec[addr + i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn step_back_over_interrupt_and_mret() {
+        let mut cpu = Cpu::new(Csrs::new());
+        // addi x1, x0, 1
+        write_words(&mut cpu, 0, &[0x00100093]);
+        // addi x2, x0, 2; mret
+        write_words(&mut cpu, 0x100, &[0x00200113, 0x30200073]);
+        cpu.trap.mtvec = 0x100;
+        cpu.trap.enabled = true;
+        cpu.add_hook(Box::new(Recorder::new(1024 * 1024)));
+
+        cpu.tick().unwrap();
+        cpu.trap.pending = true;
+        let before_interrupt = cpu.trap;
+        cpu.tick().unwrap();
+        assert_eq!((cpu.pc, cpu.trap.mepc, cpu.trap.enabled), (0x104, 4, false));
+        let in_handler = cpu.trap;
+        cpu.tick().unwrap();
+        assert_eq!((cpu.pc, cpu.trap.enabled), (4, true));
+
+        assert!(step_back(&mut cpu).unwrap());
+        assert_eq!(cpu.pc, 0x104);
+        assert!(cpu.trap == in_handler);
+        assert!(step_back(&mut cpu).unwrap());
+        assert_eq!((cpu.pc, cpu.registers[2]), (4, 0));
+        assert!(cpu.trap == before_interrupt);
+        assert!(step_back(&mut cpu).unwrap());
+        assert_eq!((cpu.pc, cpu.registers[1]), (0, 0));
+        assert!(!step_back(&mut cpu).unwrap());
+    }
+
+    #[test]
+    fn recording_stays_within_budget() {
+        let mut cpu = Cpu::new(Csrs::new());
+        // addi x1, x1, 1; jal x0, -4
+        write_words(&mut cpu, 0, &[0x00108093, 0xffdff06f]);
+        cpu.add_hook(Box::new(Recorder::new(10 * size_of::<Delta>())));
+
+        for _ in 0..100 {
+            cpu.tick().unwrap();
+        }
+        let recorder = cpu.hook_mut::<Recorder>().unwrap();
+        assert_eq!(recorder.len(), 10);
+        assert!(recorder.deltas.capacity() <= 10);
+        for _ in 0..10 {
+            assert!(step_back(&mut cpu).unwrap());
+        }
+        assert_eq!((cpu.pc, cpu.registers[1]), (0, 45));
+        assert!(!step_back(&mut cpu).unwrap());
+        assert_eq!(cpu.hook_mut::<Recorder>().unwrap().used, 0);
+
+        assert!(budget_from_mib(usize::MAX / 1024).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use super::{
+    cpu::{ABI_NAMES, Cpu},
+    memory::MemAccessSize,
+};
+use crate::dwarf::Place;
+
+/// Instructions a source level step executes at most, so it ends in loops on a single line
+const MAX_STEPS: u64 = 10_000_000;
+
+/// Tick until `done` returns true, or a breakpoint or watchpoint is hit.
+///
+/// Returns `false` if it gave up after [`MAX_STEPS`] instructions.
+fn run(cpu: &mut Cpu, mut done: impl FnMut(&Cpu) -> bool) -> Result<bool> {
+    for _ in 0..MAX_STEPS {
+        cpu.tick()?;
+        if done(cpu) || cpu.breakpoints.contains(&cpu.pc) || cpu.hit_watchpoint() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Step until the source line changes, entering calls.
+pub fn step_line(cpu: &mut Cpu) -> Result<bool> {
+    let symbols = Arc::clone(&cpu.symbols);
+    let start = symbols
+        .lines
+        .lookup(cpu.pc)
+        .context("no line info for the pc, use step")?;
+    run(cpu, |cpu| {
+        symbols
+            .lines
+            .lookup(cpu.pc)
+            .is_some_and(|line| line != start)
+    })
+}
+
+/// Step until the source line changes, stepping over calls.
+pub fn next_line(cpu: &mut Cpu) -> Result<bool> {
+    let symbols = Arc::clone(&cpu.symbols);
+    let start = symbols
+        .lines
+        .lookup(cpu.pc)
+        .context("no line info for the pc, use step")?;
+    let mut depth = 0u32;
+    run(cpu, |cpu| {
+        if cpu.commit.is_call() {
+            depth += 1;
+        } else if cpu.commit.is_return() && depth > 0 {
+            depth -= 1;
+        }
+        depth == 0
+            && symbols
+                .lines
+                .lookup(cpu.pc)
+                .is_some_and(|line| line != start)
+    })
+}
+
+/// Run until the current function returns.
+pub fn finish(cpu: &mut Cpu) -> Result<bool> {
+    let mut depth = 0u32;
+    run(cpu, |cpu| {
+        if cpu.commit.is_call() {
+            depth += 1;
+        } else if cpu.commit.is_return() {
+            if depth == 0 {
+                return true;
+            }
+            depth -= 1;
+        }
+        false
+    })
+}
+
+/// Source location of the pc, or its symbol without line info.
+pub fn location(cpu: &Cpu) -> String {
+    match cpu.symbols.lines.lookup(cpu.pc) {
+        Some((file, line)) => format!("{file}:{line}"),
+        None => cpu.symbols.format(cpu.pc),
+    }
+}
+
+/// Values of the parameters and local variables of the function at the pc.
+pub fn locals(cpu: &Cpu) -> Vec<String> {
+    let Some(scope) = cpu
+        .symbols
+        .scopes
+        .iter()
+        .find(|scope| (scope.low_pc..scope.high_pc).contains(&cpu.pc))
+    else {
+        return vec![];
+    };
+
+    let mut lines = vec![];
+    for variable in &scope.variables {
+        let size = match variable.size {
+            Some(1) => Some(MemAccessSize::Byte),
+            Some(2) => Some(MemAccessSize::HalfWord),
+            Some(4) | None => Some(MemAccessSize::Word),
+            Some(_) => None,
+        };
+        let name = &variable.name;
+        lines.push(match (scope.place(variable, &cpu.registers), size) {
+            (Some(Place::Register(reg)), _) => {
+                let value = cpu.registers[reg];
+                format!(
+                    "{name} = 0x{value:08x} {} [{}]",
+                    value as i32, ABI_NAMES[reg]
+                )
+            }
+            (Some(Place::Memory(addr)), Some(size)) => match cpu.mem.read(addr, size) {
+                Ok(value) => format!("{name} = 0x{value:08x} {} [0x{addr:08x}]", value as i32),
+                Err(_) => format!("{name} = <unreadable 0x{addr:08x}>"),
+            },
+            (Some(Place::Memory(addr)), None) => {
+                format!(
+                    "{name}: {} bytes at 0x{addr:08x}",
+                    variable.size.unwrap_or(0)
+                )
+            }
+            (None, _) => format!("{name} = <unavailable>"),
+        });
+    }
+    lines
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+
+use super::{
+    cpu::Cpu,
+    disassembler::mnemonic,
+    hook::{Commit, Hook},
+    memory::MemAccessSize,
+};
+
+/// Counts of executed instructions and device accesses
+#[derive(Default, Clone)]
+pub struct Statistics {
+    pub instructions: u64,
+    pub mnemonics: HashMap<&'static str, u64>,
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    /// Loads by size: byte, halfword, word
+    pub loads: [u64; 3],
+    /// Stores by size: byte, halfword, word
+    pub stores: [u64; 3],
+    /// Reads and writes per csr device
+    pub csr_accesses: HashMap<&'static str, (u64, u64)>,
+}
+
+pub const SIZE_NAMES: [&str; 3] = ["byte", "halfword", "word"];
+
+fn size_index(size: MemAccessSize) -> usize {
+    match size {
+        MemAccessSize::Byte => 0,
+        MemAccessSize::HalfWord => 1,
+        MemAccessSize::Word => 2,
+    }
+}
+
+impl Statistics {
+    /// Mnemonics sorted by count, most executed first.
+    pub fn top_mnemonics(&self) -> Vec<(&'static str, u64)> {
+        let mut mnemonics: Vec<_> = self.mnemonics.iter().map(|(&k, &v)| (k, v)).collect();
+        mnemonics.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        mnemonics
+    }
+
+    pub fn to_json(&self) -> String {
+        let sorted = |map: &HashMap<&'static str, u64>| -> BTreeMap<&'static str, u64> {
+            map.iter().map(|(&k, &v)| (k, v)).collect()
+        };
+        let by_size = |counts: &[u64; 3]| {
+            let fields: Vec<_> = SIZE_NAMES
+                .iter()
+                .zip(counts)
+                .map(|(name, count)| format!("\"{name}\": {count}"))
+                .collect();
+            format!("{{ {} }}", fields.join(", "))
+        };
+
+        let mut j = String::new();
+        writeln!(j, "{{").unwrap();
+        writeln!(j, "  \"instructions\": {},", self.instructions).unwrap();
+        writeln!(j, "  \"mnemonics\": {{").unwrap();
+        let mnemonics: Vec<_> = sorted(&self.mnemonics)
+            .into_iter()
+            .map(|(name, count)| format!("    \"{name}\": {count}"))
+            .collect();
+        if !mnemonics.is_empty() {
+            writeln!(j, "{}", mnemonics.join(",\n")).unwrap();
+        }
+        writeln!(j, "  }},").unwrap();
+        writeln!(
+            j,
+            "  \"branches\": {{ \"taken\": {}, \"not_taken\": {} }},",
+            self.branches_taken, self.branches_not_taken
+        )
+        .unwrap();
+        writeln!(j, "  \"loads\": {},", by_size(&self.loads)).unwrap();
+        writeln!(j, "  \"stores\": {},", by_size(&self.stores)).unwrap();
+        writeln!(j, "  \"csr\": {{").unwrap();
+        let csr: BTreeMap<_, _> = self.csr_accesses.iter().collect();
+        let csr: Vec<_> = csr
+            .into_iter()
+            .map(|(name, (reads, writes))| {
+                format!("    \"{name}\": {{ \"reads\": {reads}, \"writes\": {writes} }}")
+            })
+            .collect();
+        if !csr.is_empty() {
+            writeln!(j, "{}", csr.join(",\n")).unwrap();
+        }
+        writeln!(j, "  }}").unwrap();
+        writeln!(j, "}}").unwrap();
+        j
+    }
+}
+
+/// Collects [`Statistics`] and shares them with the GUI.
+pub struct StatsHook {
+    stats: Statistics,
+    pub shared: Arc<Mutex<Statistics>>,
+    /// Written as JSON when the emulator exits
+    pub path: Option<PathBuf>,
+}
+
+impl StatsHook {
+    pub fn new(shared: Arc<Mutex<Statistics>>, path: Option<PathBuf>) -> Self {
+        Self {
+            stats: Statistics::default(),
+            shared,
+            path,
+        }
+    }
+
+    pub fn write(&self, path: &PathBuf) -> Result<()> {
+        std::fs::write(path, self.stats.to_json())
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+impl Hook for StatsHook {
+    fn retire(&mut self, cpu: &Cpu, commit: &Commit) -> Result<()> {
+        let stats = &mut self.stats;
+        stats.instructions += 1;
+        *stats.mnemonics.entry(mnemonic(commit.insn)).or_default() += 1;
+        match commit.branch_taken {
+            Some(true) => stats.branches_taken += 1,
+            Some(false) => stats.branches_not_taken += 1,
+            None => {}
+        }
+        if let Some((_, size)) = commit.load {
+            stats.loads[size_index(size)] += 1;
+        }
+        if let Some((_, size, _)) = commit.store {
+            stats.stores[size_index(size)] += 1;
+        }
+        if let Some(csr) = commit.csr_read {
+            let name = cpu.csrs.name(csr).unwrap_or("unknown");
+            stats.csr_accesses.entry(name).or_default().0 += 1;
+        }
+        if let Some((csr, _)) = commit.csr_write {
+            let name = cpu.csrs.name(csr).unwrap_or("unknown");
+            stats.csr_accesses.entry(name).or_default().1 += 1;
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self) {
+        self.shared.lock().unwrap().clone_from(&self.stats);
+    }
+
+    fn finish(&mut self, _cpu: &Cpu) -> Result<()> {
+        match &self.path {
+            Some(path) => self.write(path),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+};
+
+use anyhow::{Context, Result};
+
+use super::{
+    cpu::Cpu,
+    disassembler::disassemble,
+    hook::{Commit, Hook},
+    memory::MemAccessSize,
+};
+
+/// Logs executed instructions in the format of Spike's `-l --log-commits` output,
+/// so traces can be diffed against Spike.
+pub struct Tracer {
+    out: BufWriter<File>,
+    pub enabled: bool,
+    /// Only trace instructions with a pc in this range
+    pub range: Option<Range<u32>>,
+    /// Don't trace the first `skip` instructions
+    pub skip: u64,
+    /// Stop tracing after this many instructions
+    pub limit: Option<u64>,
+    written: u64,
+}
+
+impl Tracer {
+    pub fn new(path: &str) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("failed to create {path}"))?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            enabled: true,
+            range: None,
+            skip: 0,
+            limit: None,
+            written: 0,
+        })
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+impl Hook for Tracer {
+    fn retire(&mut self, cpu: &Cpu, commit: &Commit) -> Result<()> {
+        if !self.enabled
+            || cpu.insn_count <= self.skip
+            || self.limit.is_some_and(|limit| self.written >= limit)
+            || self
+                .range
+                .as_ref()
+                .is_some_and(|range| !range.contains(&commit.pc))
+        {
+            return Ok(());
+        }
+        self.written += 1;
+
+        let out = &mut self.out;
+        writeln!(
+            out,
+            "core   0: 0x{:08x} (0x{:08x}) {}",
+            commit.pc,
+            commit.insn,
+            disassemble(commit.insn)
+        )?;
+        write!(
+            out,
+            "core   0: 3 0x{:08x} (0x{:08x})",
+            commit.pc, commit.insn
+        )?;
+        if let Some((reg, value)) = commit.reg_write {
+            write!(out, " x{reg:<2} 0x{value:08x}")?;
+        }
+        if let Some((addr, _)) = commit.load {
+            write!(out, " mem 0x{addr:08x}")?;
+        }
+        if let Some((addr, size, value)) = commit.store {
+            match size {
+                MemAccessSize::Byte => write!(out, " mem 0x{addr:08x} 0x{:02x}", value as u8)?,
+                MemAccessSize::HalfWord => write!(out, " mem 0x{addr:08x} 0x{:04x}", value as u16)?,
+                MemAccessSize::Word => write!(out, " mem 0x{addr:08x} 0x{value:08x}")?,
+            }
+        }
+        if let Some((csr, value)) = commit.csr_write {
+            write!(out, " c{csr} 0x{value:08x}")?;
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+
+    fn finish(&mut self, _cpu: &Cpu) -> Result<()> {
+        self.flush()
+    }
+}
@@ -2,6 +2,12 @@ use std::collections::HashMap;
 
 use anyhow::{Result, bail};
 
+use crate::{
+    cpu_thread::memory::Memory,
+    savestate::{StateReader, StateWriter},
+};
+
+#[derive(Default)]
 pub struct Csrs {
     map: HashMap<u32, usize>,
     csrs: Vec<Box<dyn Csr>>,
@@ -30,9 +36,93 @@ impl Csrs {
         };
         Ok(&mut *self.csrs[*idx])
     }
+
+    /// Name of the device handling `csr`.
+    pub fn name(&self, csr: u32) -> Option<&'static str> {
+        let idx = self.map.get(&csr)?;
+        Some(self.csrs[*idx].name())
+    }
+
+    /// Reset every device, called when the CPU is reset.
+    pub fn reset(&mut self) {
+        for csr in &mut self.csrs {
+            csr.reset();
+        }
+    }
+
+    /// Let every device do its background work, returns whether any raised an interrupt.
+    pub fn poll(&mut self, mem: &mut Memory) -> Result<bool> {
+        let mut interrupt = false;
+        for csr in &mut self.csrs {
+            interrupt |= csr.poll(mem)?;
+        }
+        Ok(interrupt)
+    }
+
+    /// Save the state of every device, in the order they were inserted.
+    pub fn save(&mut self, w: &mut StateWriter) {
+        w.u32(self.csrs.len() as u32);
+        for csr in &mut self.csrs {
+            let mut state = StateWriter::new();
+            csr.save(&mut state);
+            w.bytes(&state.into_inner());
+        }
+    }
+
+    /// Reset every device and restore the state written by [`Csrs::save`].
+    ///
+    /// If a device rejects its state, every device goes back to the state it had before.
+    pub fn load(&mut self, r: &mut StateReader, mem: &Memory) -> Result<()> {
+        let states = self.read_states(r)?;
+        let mut backup = StateWriter::new();
+        self.save(&mut backup);
+        let result = self.apply(&states, mem);
+        if result.is_err() {
+            let backup = backup.into_inner();
+            let states = self.read_states(&mut StateReader::new(&backup))?;
+            self.apply(&states, mem)?;
+        }
+        result
+    }
+
+    fn read_states<'a>(&self, r: &mut StateReader<'a>) -> Result<Vec<&'a [u8]>> {
+        if r.u32()? as usize != self.csrs.len() {
+            bail!("[savestate] device count mismatch");
+        }
+        self.csrs.iter().map(|_| r.bytes()).collect()
+    }
+
+    fn apply(&mut self, states: &[&[u8]], mem: &Memory) -> Result<()> {
+        self.reset();
+        for (csr, state) in self.csrs.iter_mut().zip(states) {
+            csr.load(&mut StateReader::new(state), mem)?;
+        }
+        Ok(())
+    }
 }
 
 pub trait Csr: Send {
-    fn read(&mut self, csr: u32, ram: &mut [u8]) -> Result<u32>;
-    fn write(&mut self, csr: u32, ram: &mut [u8], data: u32) -> Result<()>;
+    /// Short name of the device, used in statistics
+    fn name(&self) -> &'static str;
+
+    fn read(&mut self, csr: u32, mem: &mut Memory) -> Result<u32>;
+    fn write(&mut self, csr: u32, mem: &mut Memory, data: u32) -> Result<()>;
+
+    /// Called every few hundred instructions, for work that isn't triggered by an access.
+    ///
+    /// Returns true to raise an interrupt.
+    fn poll(&mut self, _mem: &mut Memory) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Forget state belonging to the program that ran before a reset.
+    fn reset(&mut self) {}
+
+    /// Save the internal state of the device for a save state.
+    fn save(&mut self, _w: &mut StateWriter) {}
+
+    /// Restore state written by [`Csr::save`], for a machine with the same amount of RAM.
+    fn load(&mut self, _r: &mut StateReader, _mem: &Memory) -> Result<()> {
+        Ok(())
+    }
 }
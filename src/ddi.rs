@@ -1,39 +1,215 @@
-use std::sync::mpsc::Sender;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
 
-use anyhow::bail;
+use anyhow::{Result, bail};
 
-use crate::{csrs::Csr, display::DisplayEvent};
+use crate::{
+    cpu_thread::memory::{MemAccessSize, Memory},
+    csrs::Csr,
+    display::{DisplayEvent, Rect, default_palette},
+    framebuffer::{DrawTarget, Framebuffer, PixelFormat},
+    savestate::{StateReader, StateWriter},
+};
 
+/// Display drawing interface.
+///
+/// - 1050/1051: low/high word of the 8x8 matrix
+/// - 1052: target, x in the low and y in the high half word
+/// - 1053: source, a screen position like the target, or the RAM address of a blit
+/// - 1054: size, width in the low and height in the high half word
+/// - 1055: color, 0xRRGGBB, or a palette index in indexed mode
+/// - 1056: draw the matrix at the target in the color
+/// - 1057: fill the screen with the color
+/// - 1058: copy the rectangle at the source to the target
+/// - 1059: draw a rectangle at the target in the color
+/// - 1060: blit the image at the source address in RAM to the target
+/// - 1061: blit format, bits per pixel (1, 2, 4, 8 or 32) in the low byte,
+///   bit 8 makes pixels of the color key transparent
+/// - 1062: RAM address of the palette of 0xRRGGBB words for 1 to 8 bits per pixel
+/// - 1063: color key, 0xRRGGBB
+/// - 1064: RAM address of the linear framebuffer
+/// - 1065: framebuffer format, 0 for off, 1 for XRGB8888, 2 for RGB565 or 3 for
+///   8 bit palette indices.
+///   While it is on the display shows the framebuffer and the commands draw into it.
+/// - 1066: read the number of frames presented by the display, writes are ignored
+/// - 1067: write 1 to draw into a back buffer that is shown by a present, 0 to draw
+///   to the screen directly
+/// - 1068: present the back buffer at the next frame, with the framebuffer on the
+///   framebuffer is captured at this point instead of every frame
+/// - 1069: write 1 to raise an interrupt on every frame, 0 to stop
+/// - 1070: restrict drawing to the rectangle at the target of the size
+/// - 1071: draw on the whole screen again
+/// - 1072/1073/1074: points A, B and C, signed x in the low and y in the high half word
+/// - 1075: draw a line from A to B in the color
+/// - 1076/1077: draw the outline of/fill an ellipse around A, the size holds the radii
+/// - 1078: fill the triangle A, B, C
+/// - 1079: fill the polygon with vertices at the source address in RAM, words like the
+///   points, their number is in the low half word of the size
+/// - 1080: write 1 for indexed mode, where colors, blitted pixels and the color key are
+///   indices into the screen palette and the screen is cleared to index 0, 0 for colors
+/// - 1081: load the screen palette from 256 0xRRGGBB words at this RAM address
+/// - 1082: set one entry of the screen palette, the index in the high byte and the
+///   color in the low 24 bits
+/// - 1083: read the resolution, width in the low and height in the high half word,
+///   writes are ignored
 pub struct DdiCsr {
-    send: Sender<DisplayEvent>,
+    output: DrawTarget,
+    /// Frames presented by the display
+    frames: Arc<AtomicU64>,
+    /// Frame the framebuffer was last scanned out for
+    scanned: u64,
+    /// Frame the last vblank interrupt was raised for
+    vblank: u64,
+    vblank_interrupt: bool,
+    double_buffered: bool,
     matrix_1: u32,
     matrix_2: u32,
     target: u32,
     source: u32,
     size: u32,
     color: u32,
+    format: u32,
+    palette: u32,
+    key: u32,
+    framebuffer_base: u32,
+    points: [u32; 3],
+    indexed: bool,
+    screen_palette: Box<[u32; 256]>,
+}
+
+/// Polygons with more vertices are rejected
+const MAX_POLYGON_VERTICES: u32 = 4096;
+
+/// A point latch, the coordinates are signed so shapes can start off screen
+fn point(latch: u32) -> (i32, i32) {
+    (latch as i16 as i32, (latch >> 16) as i16 as i32)
 }
 
 impl DdiCsr {
-    pub fn new(send: Sender<DisplayEvent>) -> Self {
+    pub fn new(output: DrawTarget, frames: Arc<AtomicU64>) -> Self {
         Self {
-            send,
+            output,
+            frames,
+            scanned: 0,
+            vblank: 0,
+            vblank_interrupt: false,
+            double_buffered: false,
             matrix_1: 0,
             matrix_2: 0,
             target: 0,
             source: 0,
             size: 0,
             color: 0,
+            format: 32,
+            palette: 0,
+            key: 0,
+            framebuffer_base: 0,
+            points: [0; 3],
+            indexed: false,
+            screen_palette: default_palette(),
+        }
+    }
+
+    fn resolution_word(&self) -> u32 {
+        let resolution = self.output.resolution();
+        (resolution.height as u32) << 16 | resolution.width as u32
+    }
+
+    fn send_palette(&self) {
+        self.output.send(DisplayEvent::Palette {
+            palette: self.screen_palette.clone(),
+        });
+    }
+
+    /// Turn the framebuffer on with `format` at the latched base, or off.
+    fn set_framebuffer(&mut self, mem: &Memory, format: u32) -> Result<()> {
+        if format == 0 {
+            self.output.set_framebuffer(None);
+            return Ok(());
         }
+        let Some(format) = PixelFormat::from_u32(format) else {
+            bail!("[ddi] unknown framebuffer format {format}");
+        };
+        let framebuffer = Framebuffer {
+            base: self.framebuffer_base,
+            format,
+            resolution: self.output.resolution(),
+        };
+        framebuffer.check(mem)?;
+        self.output.set_framebuffer(Some(framebuffer));
+        // Show it on the next frame
+        self.scanned = self.frames.load(Ordering::Relaxed).wrapping_sub(1);
+        Ok(())
+    }
+
+    /// Read the part of the image at the source address that lies in `area`, rows start
+    /// on a byte boundary and the leftmost pixel is in the most significant bits of a byte.
+    ///
+    /// In indexed mode pixels of up to 8 bits are palette indices themselves.
+    fn blit_pixels(&self, mem: &Memory, area: Rect) -> Result<Vec<Option<u32>>> {
+        let (size_x, size_y) = (self.size & 0xFFFF, self.size >> 16);
+        let bpp = self.format & 0xFF;
+        let transparent = self.format & 0x100 != 0;
+        let row_bytes = match bpp {
+            1 | 2 | 4 | 8 => (size_x * bpp).div_ceil(8),
+            32 => size_x * 4,
+            _ => bail!("[ddi] unsupported blit format of {bpp} bits per pixel"),
+        };
+        // Addresses inside checked ranges fit in RAM, so they can't overflow below
+        let in_ram = |start: u32, len: u64| start as u64 + len <= mem.vec.len() as u64;
+        if !in_ram(self.source, row_bytes as u64 * size_y as u64) {
+            bail!(
+                "[ddi] blit of {size_x}x{size_y} at 0x{:08x} reads outside of RAM",
+                self.source
+            );
+        }
+        if bpp != 32 && !self.indexed && !in_ram(self.palette, 4 << bpp) {
+            bail!(
+                "[ddi] blit palette at 0x{:08x} is outside of RAM",
+                self.palette
+            );
+        }
+
+        let (target_x, target_y) = (self.target & 0xFFFF, self.target >> 16);
+        let mut pixels = Vec::with_capacity((area.width * area.height) as usize);
+        for y in area.y as u32..(area.y + area.height) as u32 {
+            let row = self.source + (y - target_y) * row_bytes;
+            for x in area.x as u32 - target_x..(area.x + area.width) as u32 - target_x {
+                let color = if bpp == 32 {
+                    mem.read(row + x * 4, MemAccessSize::Word)?
+                } else {
+                    let bit = x * bpp;
+                    let byte = mem.read(row + bit / 8, MemAccessSize::Byte)?;
+                    let index = (byte >> (8 - bpp - bit % 8)) & ((1 << bpp) - 1);
+                    if self.indexed {
+                        index
+                    } else {
+                        mem.read(self.palette + index * 4, MemAccessSize::Word)?
+                    }
+                } & if self.indexed { 0xFF } else { 0xFFFFFF };
+                pixels.push((!transparent || color != self.key).then_some(color));
+            }
+        }
+        Ok(pixels)
     }
 }
 
 impl Csr for DdiCsr {
-    fn read(&mut self, _csr: u32, _ram: &mut [u8]) -> anyhow::Result<u32> {
-        bail!("No read from ddi");
+    fn name(&self) -> &'static str {
+        "ddi"
     }
 
-    fn write(&mut self, csr: u32, _ram: &mut [u8], data: u32) -> anyhow::Result<()> {
+    fn read(&mut self, csr: u32, _mem: &mut Memory) -> Result<u32> {
+        match csr {
+            1066 => Ok(self.frames.load(Ordering::Relaxed) as u32),
+            1083 => Ok(self.resolution_word()),
+            _ => bail!("No read from ddi"),
+        }
+    }
+
+    fn write(&mut self, csr: u32, mem: &mut Memory, data: u32) -> Result<()> {
         match csr {
             1050 => self.matrix_1 = data,
             1051 => self.matrix_2 = data,
@@ -41,32 +217,275 @@ impl Csr for DdiCsr {
             1053 => self.source = data,
             1054 => self.size = data,
             1055 => self.color = data,
-            1056 => self
-                .send
-                .send(DisplayEvent::Matrix {
+            1056 => self.output.draw(
+                mem,
+                DisplayEvent::Matrix {
                     matrix: ((self.matrix_2 as u64) << 32) | self.matrix_1 as u64,
                     target_x: (self.target & 0xFFFF) as u16,
                     target_y: (self.target >> 16) as u16,
                     color: self.color,
-                })
-                .unwrap(),
+                },
+            ),
             1057 => self
-                .send
-                .send(DisplayEvent::Floodfill { color: self.color })
-                .unwrap(),
-            1058 => bail!("ddi copy not implemented"),
-            1059 => self
-                .send
-                .send(DisplayEvent::Rectangle {
+                .output
+                .draw(mem, DisplayEvent::Floodfill { color: self.color }),
+            1058 => self.output.draw(
+                mem,
+                DisplayEvent::Copy {
+                    target_x: (self.target & 0xFFFF) as u16,
+                    target_y: (self.target >> 16) as u16,
+                    source_x: (self.source & 0xFFFF) as u16,
+                    source_y: (self.source >> 16) as u16,
+                    size_x: (self.size & 0xFFFF) as u16,
+                    size_y: (self.size >> 16) as u16,
+                },
+            ),
+            1059 => self.output.draw(
+                mem,
+                DisplayEvent::Rectangle {
                     target_x: (self.target & 0xFFFF) as u16,
                     target_y: (self.target >> 16) as u16,
                     size_x: (self.size & 0xFFFF) as u16,
                     size_y: (self.size >> 16) as u16,
                     color: self.color,
-                })
-                .unwrap(),
+                },
+            ),
+            1060 => {
+                // Only the visible part is read and sent, images can be large
+                let bounds = Rect::new(
+                    (self.target & 0xFFFF) as u16,
+                    (self.target >> 16) as u16,
+                    (self.size & 0xFFFF) as u16,
+                    (self.size >> 16) as u16,
+                );
+                self.output.check_bounds(bounds);
+                let area = bounds
+                    .intersect(&self.output.resolution().rect())
+                    .intersect(&self.output.clip());
+                let pixels = self.blit_pixels(mem, area)?;
+                if !pixels.is_empty() {
+                    self.output.draw(
+                        mem,
+                        DisplayEvent::Blit {
+                            target_x: area.x as u16,
+                            target_y: area.y as u16,
+                            size_x: area.width as u16,
+                            size_y: area.height as u16,
+                            pixels,
+                        },
+                    );
+                }
+            }
+            1061 => self.format = data,
+            1062 => self.palette = data,
+            1063 => self.key = data,
+            1064 => {
+                self.framebuffer_base = data;
+                if let Some(framebuffer) = self.output.framebuffer() {
+                    self.set_framebuffer(mem, framebuffer.format.to_u32())?;
+                }
+            }
+            1065 => self.set_framebuffer(mem, data)?,
+            1066 | 1083 => {}
+            1067 => {
+                self.double_buffered = data != 0;
+                self.output.send(DisplayEvent::DoubleBuffer {
+                    enabled: self.double_buffered,
+                });
+            }
+            1068 => match self.output.framebuffer() {
+                Some(framebuffer) => self.output.send(DisplayEvent::Scanout {
+                    format: framebuffer.format,
+                    data: framebuffer.read(mem),
+                }),
+                None => self.output.send(DisplayEvent::Present),
+            },
+            1069 => {
+                self.vblank_interrupt = data != 0;
+                self.vblank = self.frames.load(Ordering::Relaxed);
+            }
+            1070 => self.output.set_clip(Rect::new(
+                (self.target & 0xFFFF) as u16,
+                (self.target >> 16) as u16,
+                (self.size & 0xFFFF) as u16,
+                (self.size >> 16) as u16,
+            )),
+            1071 => self.output.set_clip(self.output.resolution().rect()),
+            1072..=1074 => self.points[(csr - 1072) as usize] = data,
+            1075 => self.output.draw(
+                mem,
+                DisplayEvent::Line {
+                    from: point(self.points[0]),
+                    to: point(self.points[1]),
+                    color: self.color,
+                },
+            ),
+            1076 | 1077 => self.output.draw(
+                mem,
+                DisplayEvent::Ellipse {
+                    center: point(self.points[0]),
+                    radius_x: (self.size & 0xFFFF) as i32,
+                    radius_y: (self.size >> 16) as i32,
+                    filled: csr == 1077,
+                    color: self.color,
+                },
+            ),
+            1078 => self.output.draw(
+                mem,
+                DisplayEvent::Polygon {
+                    points: self.points.map(point).to_vec(),
+                    color: self.color,
+                },
+            ),
+            1079 => {
+                let count = self.size & 0xFFFF;
+                if count > MAX_POLYGON_VERTICES {
+                    bail!(
+                        "[ddi] polygon with {count} vertices, at most {MAX_POLYGON_VERTICES} are supported"
+                    );
+                }
+                let points = (0..count)
+                    .map(|i| {
+                        let addr = self.source.wrapping_add(i * 4);
+                        Ok(point(mem.read(addr, MemAccessSize::Word)?))
+                    })
+                    .collect::<Result<_>>()?;
+                self.output.draw(
+                    mem,
+                    DisplayEvent::Polygon {
+                        points,
+                        color: self.color,
+                    },
+                );
+            }
+            1080 => {
+                self.indexed = data != 0;
+                self.output.send(DisplayEvent::Indexed {
+                    enabled: self.indexed,
+                });
+            }
+            1081 => {
+                for (i, entry) in self.screen_palette.iter_mut().enumerate() {
+                    let addr = data.wrapping_add(i as u32 * 4);
+                    *entry = mem.read(addr, MemAccessSize::Word)? & 0xFFFFFF;
+                }
+                self.send_palette();
+            }
+            1082 => {
+                self.screen_palette[(data >> 24) as usize] = data & 0xFFFFFF;
+                self.send_palette();
+            }
             _ => unreachable!(),
         }
         Ok(())
     }
+
+    fn poll(&mut self, mem: &mut Memory) -> Result<bool> {
+        let frames = self.frames.load(Ordering::Relaxed);
+        // Double buffered framebuffers are only scanned out on present
+        if let Some(framebuffer) = self.output.framebuffer()
+            && !self.double_buffered
+            && frames != self.scanned
+        {
+            self.scanned = frames;
+            self.output.send(DisplayEvent::Scanout {
+                format: framebuffer.format,
+                data: framebuffer.read(mem),
+            });
+        }
+        let vblank = frames != self.vblank;
+        self.vblank = frames;
+        Ok(vblank && self.vblank_interrupt)
+    }
+
+    fn reset(&mut self) {
+        self.framebuffer_base = 0;
+        self.output.set_framebuffer(None);
+        self.output.set_clip(self.output.resolution().rect());
+        if self.indexed {
+            self.indexed = false;
+            self.output.send(DisplayEvent::Indexed { enabled: false });
+        }
+        self.screen_palette = default_palette();
+        self.send_palette();
+        self.vblank_interrupt = false;
+        if self.double_buffered {
+            self.double_buffered = false;
+            self.output
+                .send(DisplayEvent::DoubleBuffer { enabled: false });
+        }
+    }
+
+    fn save(&mut self, w: &mut StateWriter) {
+        w.u32(self.matrix_1);
+        w.u32(self.matrix_2);
+        w.u32(self.target);
+        w.u32(self.source);
+        w.u32(self.size);
+        w.u32(self.color);
+        w.u32(self.format);
+        w.u32(self.palette);
+        w.u32(self.key);
+        w.u32(self.framebuffer_base);
+        w.u32(self.output.framebuffer().map_or(0, |fb| fb.format.to_u32()));
+        w.u32(self.vblank_interrupt as u32);
+        w.u32(self.double_buffered as u32);
+        let clip = self.output.clip();
+        for value in [clip.x, clip.y, clip.width, clip.height] {
+            w.u32(value as u32);
+        }
+        for point in self.points {
+            w.u32(point);
+        }
+        w.u32(self.indexed as u32);
+        for &color in self.screen_palette.iter() {
+            w.u32(color);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader, mem: &Memory) -> Result<()> {
+        self.matrix_1 = r.u32()?;
+        self.matrix_2 = r.u32()?;
+        self.target = r.u32()?;
+        self.source = r.u32()?;
+        self.size = r.u32()?;
+        self.color = r.u32()?;
+        self.format = r.u32()?;
+        self.palette = r.u32()?;
+        self.key = r.u32()?;
+        self.framebuffer_base = r.u32()?;
+        let format = r.u32()?;
+        let framebuffer = PixelFormat::from_u32(format).map(|format| Framebuffer {
+            base: self.framebuffer_base,
+            format,
+            resolution: self.output.resolution(),
+        });
+        if let Some(framebuffer) = framebuffer {
+            framebuffer.check(mem)?;
+        }
+        self.output.set_framebuffer(framebuffer);
+        self.vblank_interrupt = r.u32()? != 0;
+        self.double_buffered = r.u32()? != 0;
+        self.output.send(DisplayEvent::DoubleBuffer {
+            enabled: self.double_buffered,
+        });
+        self.output.set_clip(Rect {
+            x: r.u32()? as i32,
+            y: r.u32()? as i32,
+            width: r.u32()? as i32,
+            height: r.u32()? as i32,
+        });
+        for point in &mut self.points {
+            *point = r.u32()?;
+        }
+        self.indexed = r.u32()? != 0;
+        self.output.send(DisplayEvent::Indexed {
+            enabled: self.indexed,
+        });
+        for color in self.screen_palette.iter_mut() {
+            *color = r.u32()?;
+        }
+        self.send_palette();
+        Ok(())
+    }
 }
@@ -11,7 +11,11 @@ use ratatui::{
     widgets::Widget,
 };
 
-use crate::csrs::Csr;
+use crate::{
+    cpu_thread::memory::Memory,
+    csrs::Csr,
+    savestate::{StateReader, StateWriter},
+};
 
 pub enum DebugDisplayMessage {
     S(String),
@@ -61,6 +65,9 @@ impl DebugDisplay {
 
 impl Widget for &DebugDisplay {
     fn render(self, area: Rect, buf: &mut Buffer) {
+        if area.is_empty() {
+            return;
+        }
         let mut v = vec![];
         for line in self.lines.iter() {
             for line in line.as_bytes().chunks(area.width as _) {
@@ -101,7 +108,7 @@ impl DebugDisplayCsr {
         Self { send, length: 0 }
     }
 
-    pub fn print(&mut self, ram: &mut [u8], addr: usize) {
+    pub fn print(&mut self, ram: &[u8], addr: usize) {
         let data = &ram[addr..addr + self.length];
         let data = String::from_utf8_lossy(data);
         //eprint!("{}", data);
@@ -123,13 +130,17 @@ impl DebugDisplayCsr {
 }
 
 impl Csr for DebugDisplayCsr {
-    fn read(&mut self, _csr: u32, _ram: &mut [u8]) -> Result<u32> {
+    fn name(&self) -> &'static str {
+        "debug_display"
+    }
+
+    fn read(&mut self, _csr: u32, _mem: &mut Memory) -> Result<u32> {
         bail!("No reading from debug display");
     }
 
-    fn write(&mut self, csr: u32, ram: &mut [u8], data: u32) -> Result<()> {
+    fn write(&mut self, csr: u32, mem: &mut Memory, data: u32) -> Result<()> {
         match csr {
-            1100 => self.print(ram, data as usize),
+            1100 => self.print(&mem.vec, data as usize),
             1101 => self.newline(),
             1102 => self.length(data),
             1103 => self.clear(),
@@ -137,4 +148,13 @@ impl Csr for DebugDisplayCsr {
         }
         Ok(())
     }
+
+    fn save(&mut self, w: &mut StateWriter) {
+        w.u32(self.length as u32);
+    }
+
+    fn load(&mut self, r: &mut StateReader, _mem: &Memory) -> Result<()> {
+        self.length = r.u32()? as usize;
+        Ok(())
+    }
 }
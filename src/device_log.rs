@@ -0,0 +1,44 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Occurrences of one kind of warning
+#[derive(Clone)]
+pub struct Warning {
+    pub count: u64,
+    /// Most recent message, kept so a warning raised every frame doesn't flood the log
+    pub last: String,
+}
+
+/// Warnings raised by devices for guest mistakes that aren't worth stopping the CPU for,
+/// e.g. drawing off screen
+#[derive(Clone, Default)]
+pub struct DeviceLog {
+    warnings: Arc<Mutex<BTreeMap<&'static str, Warning>>>,
+}
+
+impl DeviceLog {
+    pub fn warn(&self, kind: &'static str, message: String) {
+        let mut warnings = self.warnings.lock().unwrap();
+        let warning = warnings.entry(kind).or_insert_with(|| Warning {
+            count: 0,
+            last: String::new(),
+        });
+        warning.count += 1;
+        warning.last = message;
+    }
+
+    /// One line per kind of warning.
+    pub fn lines(&self) -> Vec<String> {
+        let warnings = self.warnings.lock().unwrap();
+        warnings
+            .iter()
+            .map(|(kind, warning)| format!("{kind}: {}x, last: {}", warning.count, warning.last))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.warnings.lock().unwrap().clear();
+    }
+}
@@ -1,17 +1,109 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, Sender},
+    },
+    time::Duration,
+};
 
 use pixels::{Pixels, SurfaceTexture};
 use winit::{
     dpi::LogicalSize,
-    event::{Event, KeyEvent, WindowEvent},
+    event::{Event, WindowEvent},
     event_loop::EventLoop,
     keyboard::KeyCode,
     window::WindowBuilder,
 };
 use winit_input_helper::WinitInputHelper;
-pub const WIDTH: u16 = 640;
-pub const HEIGHT: u16 = 480;
-pub const SCALE: u16 = 2;
+
+use crate::{
+    capture::Capture,
+    framebuffer::{self, PixelFormat},
+    keyboard::KeyInput,
+};
+
+/// Time between frames when nothing paces them, like a 60 Hz display with vsync does
+pub const FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Size of the screen in pixels, part of the machine configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Resolution {
+    pub const DEFAULT: Resolution = Resolution {
+        width: 640,
+        height: 480,
+    };
+    /// Largest width and height, so that coordinates fit the 16 bit fields of the DDI
+    pub const MAX: u16 = 4096;
+
+    pub fn pixels(self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    /// The whole screen.
+    pub fn rect(self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// Index of a pixel in row major order.
+    pub fn index(self, x: u16, y: u16) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// A rectangle on screen, in signed coordinates so off screen parts can be represented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            x: x.into(),
+            y: y.into(),
+            width: width.into(),
+            height: height.into(),
+        }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    /// Whether `other` lies completely inside.
+    pub fn encloses(&self, other: &Rect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        Rect {
+            x,
+            y,
+            width: ((self.x + self.width).min(other.x + other.width) - x).max(0),
+            height: ((self.y + self.height).min(other.y + other.height) - y).max(0),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum DisplayEvent {
@@ -33,24 +125,326 @@ pub enum DisplayEvent {
     Floodfill {
         color: u32,
     },
+    /// Draw an image, `None` pixels are transparent
+    Blit {
+        target_x: u16,
+        target_y: u16,
+        size_x: u16,
+        size_y: u16,
+        pixels: Vec<Option<u32>>,
+    },
     Matrix {
         matrix: u64,
         target_x: u16,
         target_y: u16,
         color: u32,
     },
+    /// Line including both end points
+    Line {
+        from: (i32, i32),
+        to: (i32, i32),
+        color: u32,
+    },
+    /// Ellipse, a circle if both radii are the same
+    Ellipse {
+        center: (i32, i32),
+        radius_x: i32,
+        radius_y: i32,
+        filled: bool,
+        color: u32,
+    },
+    /// Filled polygon, edges may cross, inside is decided by the even-odd rule
+    Polygon {
+        points: Vec<(i32, i32)>,
+        color: u32,
+    },
+    /// Reply with a copy of the framebuffer and of the palette indices, which are
+    /// empty unless in indexed mode
+    Snapshot {
+        reply: Sender<(Vec<u8>, Vec<u8>)>,
+    },
+    /// Replace the framebuffer and the palette indices
+    Restore {
+        frame: Vec<u8>,
+        indices: Vec<u8>,
+    },
+    /// Switch between colors and palette indices in drawing commands, the screen is
+    /// cleared to index 0 when switching to indices
+    Indexed {
+        enabled: bool,
+    },
+    Palette {
+        palette: Box<[u32; 256]>,
+    },
+    /// Draw into a back buffer instead of the frame, or stop
+    DoubleBuffer {
+        enabled: bool,
+    },
+    /// Show the back buffer
+    Present,
+    /// Restrict drawing to a rectangle
+    Clip {
+        rect: Rect,
+    },
+    /// Show the pixels of the framebuffer in RAM
+    Scanout {
+        format: PixelFormat,
+        data: Vec<u8>,
+    },
 }
 
-pub fn run(recv: Receiver<DisplayEvent>, send: Sender<KeyEvent>) {
+impl DisplayEvent {
+    /// Screen area a drawing command touches, `None` for other events and commands
+    /// that can't draw off screen.
+    pub fn bounds(&self) -> Option<Rect> {
+        match *self {
+            DisplayEvent::Rectangle {
+                target_x,
+                target_y,
+                size_x,
+                size_y,
+                ..
+            }
+            | DisplayEvent::Copy {
+                target_x,
+                target_y,
+                size_x,
+                size_y,
+                ..
+            }
+            | DisplayEvent::Blit {
+                target_x,
+                target_y,
+                size_x,
+                size_y,
+                ..
+            } => Some(Rect::new(target_x, target_y, size_x, size_y)),
+            DisplayEvent::Matrix {
+                target_x, target_y, ..
+            } => Some(Rect::new(target_x, target_y, 8, 8)),
+            DisplayEvent::Line { from, to, .. } => Some(bounding_box(&[from, to])),
+            DisplayEvent::Ellipse {
+                center: (x, y),
+                radius_x,
+                radius_y,
+                ..
+            } => Some(Rect {
+                x: x - radius_x,
+                y: y - radius_y,
+                width: 2 * radius_x + 1,
+                height: 2 * radius_y + 1,
+            }),
+            DisplayEvent::Polygon { ref points, .. } => Some(bounding_box(points)),
+            _ => None,
+        }
+    }
+}
+
+/// Smallest rectangle containing all `points`.
+fn bounding_box(points: &[(i32, i32)]) -> Rect {
+    let min_x = points.iter().map(|p| p.0).min().unwrap_or(0);
+    let min_y = points.iter().map(|p| p.1).min().unwrap_or(0);
+    let max_x = points.iter().map(|p| p.0).max().unwrap_or(-1);
+    let max_y = points.iter().map(|p| p.1).max().unwrap_or(-1);
+    Rect {
+        x: min_x,
+        y: min_y,
+        width: max_x - min_x + 1,
+        height: max_y - min_y + 1,
+    }
+}
+
+/// Palette after reset, a gray ramp
+pub fn default_palette() -> Box<[u32; 256]> {
+    Box::new(std::array::from_fn(|i| i as u32 * 0x010101))
+}
+
+/// Software framebuffer the display events are applied to, independent of where the
+/// frame is shown
+pub struct Screen {
+    resolution: Resolution,
+    /// RGBA pixels that are shown
+    frame: Vec<u8>,
+    /// Frame the drawing commands go to while double buffering is on,
+    /// palette indices in indexed mode
+    back: Option<Vec<u8>>,
+    clip: Rect,
+    palette: Box<[u32; 256]>,
+    /// Palette index of every pixel in indexed mode, the frame is rendered from them
+    indices: Option<Vec<u8>>,
+    /// The frame shows a framebuffer in RAM rather than the indices
+    scanned_out: bool,
+}
+
+impl Screen {
+    pub fn new(resolution: Resolution) -> Self {
+        Self {
+            resolution,
+            frame: vec![0; resolution.pixels() * 4],
+            back: None,
+            clip: resolution.rect(),
+            palette: default_palette(),
+            indices: None,
+            scanned_out: false,
+        }
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Apply the pending events.
+    pub fn update(&mut self, recv: &Receiver<DisplayEvent>) {
+        let Screen {
+            resolution,
+            frame,
+            back,
+            clip,
+            palette,
+            indices,
+            scanned_out,
+        } = self;
+        let frame = frame.as_mut_slice();
+        for e in recv.try_iter() {
+            match e {
+                DisplayEvent::Scanout { format, data } => {
+                    framebuffer::scan_out(format, &data, palette, frame);
+                    *scanned_out = true;
+                }
+                DisplayEvent::Snapshot { reply } => {
+                    let _ = reply.send((frame.to_vec(), indices.clone().unwrap_or_default()));
+                }
+                DisplayEvent::Restore {
+                    frame: data,
+                    indices: data_indices,
+                } => {
+                    if data.len() == frame.len() {
+                        frame.copy_from_slice(&data);
+                    }
+                    if let Some(indices) = indices
+                        && data_indices.len() == indices.len()
+                    {
+                        indices.copy_from_slice(&data_indices);
+                    }
+                    // The front buffer of whichever mode is active
+                    let front = indices.as_deref().unwrap_or(frame);
+                    if let Some(back) = back
+                        && back.len() == front.len()
+                    {
+                        back.copy_from_slice(front);
+                    }
+                }
+                DisplayEvent::DoubleBuffer { enabled } => {
+                    *back = enabled.then(|| indices.as_deref().unwrap_or(frame).to_vec());
+                }
+                DisplayEvent::Present => {
+                    *scanned_out = false;
+                    if let Some(back) = back {
+                        indices
+                            .as_deref_mut()
+                            .unwrap_or(frame)
+                            .copy_from_slice(back);
+                    }
+                }
+                DisplayEvent::Clip { rect } => *clip = rect,
+                DisplayEvent::Indexed { enabled } => {
+                    *scanned_out = false;
+                    *indices = enabled.then(|| vec![0; resolution.pixels()]);
+                    if let Some(back) = back {
+                        *back = indices.as_deref().unwrap_or(frame).to_vec();
+                    }
+                }
+                DisplayEvent::Palette { palette: new } => *palette = new,
+                e => {
+                    *scanned_out = false;
+                    let indexed = indices.is_some();
+                    let canvas = match back {
+                        Some(back) => back.as_mut_slice(),
+                        None => indices.as_deref_mut().unwrap_or(frame),
+                    };
+                    let resolution = *resolution;
+                    if indexed {
+                        draw(
+                            &mut Indices {
+                                data: canvas,
+                                resolution,
+                            },
+                            *clip,
+                            e,
+                        );
+                    } else {
+                        draw(
+                            &mut Frame {
+                                data: canvas,
+                                resolution,
+                            },
+                            *clip,
+                            e,
+                        );
+                    }
+                }
+            }
+        }
+        // Palette changes show up on the next frame
+        if let Some(indices) = indices
+            && !*scanned_out
+        {
+            for (&index, rgba) in indices.iter().zip(frame.chunks_exact_mut(4)) {
+                set_color(rgba.try_into().unwrap(), palette[index as usize]);
+            }
+        }
+    }
+}
+
+/// Palette indices, as a surface for drawing commands in indexed mode
+struct Indices<'a> {
+    data: &'a mut [u8],
+    resolution: Resolution,
+}
+
+impl Surface for Indices<'_> {
+    fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    fn get(&self, x: u16, y: u16) -> u32 {
+        self.data[self.resolution.index(x, y)].into()
+    }
+
+    fn set(&mut self, x: u16, y: u16, color: u32) {
+        self.data[self.resolution.index(x, y)] = color as u8;
+    }
+}
+
+/// Show the window, `frames` counts the presented frames.
+///
+/// The window starts at `scale` times the resolution and can be resized, the frame is
+/// scaled by the largest integer factor that fits and letterboxed.
+///
+/// F12 saves a screenshot, it isn't passed on to the guest.
+pub fn run(
+    recv: Receiver<DisplayEvent>,
+    send: Sender<KeyInput>,
+    frames: Arc<AtomicU64>,
+    resolution: Resolution,
+    scale: u16,
+    mut capture: Capture,
+) {
     let event_loop = EventLoop::new().unwrap();
     let mut input = WinitInputHelper::new();
     let window = {
-        let size = LogicalSize::new((WIDTH * SCALE) as f64, (HEIGHT * SCALE) as f64);
+        let (width, height) = (resolution.width as f64, resolution.height as f64);
         WindowBuilder::new()
             .with_title("Bobby's Display")
-            .with_inner_size(size)
-            .with_min_inner_size(size)
-            .with_resizable(false)
+            .with_inner_size(LogicalSize::new(
+                width * scale as f64,
+                height * scale as f64,
+            ))
+            .with_min_inner_size(LogicalSize::new(width, height))
             .build(&event_loop)
             .unwrap()
     };
@@ -58,27 +452,53 @@ pub fn run(recv: Receiver<DisplayEvent>, send: Sender<KeyEvent>) {
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new(WIDTH as u32, HEIGHT as u32, surface_texture).unwrap()
+        Pixels::new(
+            resolution.width as u32,
+            resolution.height as u32,
+            surface_texture,
+        )
+        .unwrap()
     };
 
+    let mut screen = Screen::new(resolution);
     let _res = event_loop.run(|event, elwt| {
         match event {
             Event::WindowEvent {
                 event: WindowEvent::RedrawRequested,
                 ..
             } => {
-                update_frame(pixels.frame_mut(), &recv);
+                screen.update(&recv);
+                pixels.frame_mut().copy_from_slice(screen.frame());
                 if let Err(err) = pixels.render() {
                     eprintln!("pixels.render {:?}", err);
                     elwt.exit();
                     return;
                 }
+                let frame_number = frames.fetch_add(1, Ordering::Relaxed) + 1;
+                if let Err(err) = capture.presented(screen.frame(), frame_number) {
+                    eprintln!("recording stopped: {:?}", err);
+                    capture.recording = None;
+                }
+            }
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            } => {
+                if let Err(err) = pixels.resize_surface(size.width, size.height) {
+                    eprintln!("pixels.resize_surface {:?}", err);
+                    elwt.exit();
+                    return;
+                }
             }
             Event::WindowEvent {
                 event: WindowEvent::KeyboardInput { ref event, .. },
                 ..
             } => {
-                send.send(event.clone()).unwrap();
+                if let Some(key) = KeyInput::from_event(event)
+                    && key.code != KeyCode::F12
+                {
+                    send.send(key).unwrap();
+                }
             }
             _ => (),
         }
@@ -87,65 +507,311 @@ pub fn run(recv: Receiver<DisplayEvent>, send: Sender<KeyEvent>) {
         if input.update(&event) {
             // Close events
             if input.key_pressed(KeyCode::Escape) || input.close_requested() {
+                if let Err(err) = capture.exit(screen.frame()) {
+                    eprintln!("screenshot {:?}", err);
+                }
                 elwt.exit();
                 return;
             }
 
+            if input.key_pressed(KeyCode::F12) {
+                let frame_number = frames.load(Ordering::Relaxed);
+                if let Err(err) = capture.screenshot(screen.frame(), frame_number) {
+                    eprintln!("screenshot {:?}", err);
+                }
+            }
+
             // Update internal state and request a redraw
             window.request_redraw();
         }
     });
 }
 
-pub fn update_frame(frame: &mut [u8], recv: &Receiver<DisplayEvent>) {
-    for e in recv.try_iter() {
-        match e {
-            DisplayEvent::Rectangle {
-                target_x,
-                target_y,
-                size_x,
-                size_y,
-                color,
-            } => {
-                for yi in 0..size_y {
-                    for xi in 0..size_x {
-                        let pixel = pixel(frame, target_x + xi, target_y + yi);
-                        set_color(pixel, color);
-                    }
+/// Pixels the drawing commands work on, colors are 0xRRGGBB
+pub trait Surface {
+    fn resolution(&self) -> Resolution;
+    fn get(&self, x: u16, y: u16) -> u32;
+    fn set(&mut self, x: u16, y: u16, color: u32);
+}
+
+/// The RGBA frame of the display
+pub struct Frame<'a> {
+    pub data: &'a mut [u8],
+    pub resolution: Resolution,
+}
+
+impl Frame<'_> {
+    fn pixel(&mut self, x: u16, y: u16) -> &mut [u8; 4] {
+        let idx = self.resolution.index(x, y) * 4;
+        (&mut self.data[idx..idx + 4]).try_into().unwrap()
+    }
+}
+
+impl Surface for Frame<'_> {
+    fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    fn get(&self, x: u16, y: u16) -> u32 {
+        let idx = self.resolution.index(x, y) * 4;
+        u32::from_be_bytes([0, self.data[idx], self.data[idx + 1], self.data[idx + 2]])
+    }
+
+    fn set(&mut self, x: u16, y: u16, color: u32) {
+        set_color(self.pixel(x, y), color);
+    }
+}
+
+/// Apply a drawing command inside `clip`, other events are ignored.
+pub fn draw<S: Surface + ?Sized>(surface: &mut S, clip: Rect, event: DisplayEvent) {
+    let clip = clip.intersect(&surface.resolution().rect());
+    let bounds = event.bounds().map(|bounds| bounds.intersect(&clip));
+    match event {
+        DisplayEvent::Rectangle { color, .. } => {
+            let area = bounds.unwrap();
+            for y in area.y..area.y + area.height {
+                for x in area.x..area.x + area.width {
+                    surface.set(x as u16, y as u16, color);
                 }
             }
-            DisplayEvent::Copy { .. } => todo!(),
-            DisplayEvent::Floodfill { color } => {
-                for pixel in frame.chunks_exact_mut(4) {
-                    set_color(pixel.try_into().unwrap(), color);
+        }
+        DisplayEvent::Copy {
+            target_x,
+            target_y,
+            source_x,
+            source_y,
+            size_x,
+            size_y,
+        } => copy(
+            surface,
+            clip,
+            (source_x, source_y),
+            (target_x, target_y),
+            (size_x, size_y),
+        ),
+        DisplayEvent::Floodfill { color } => {
+            for y in clip.y..clip.y + clip.height {
+                for x in clip.x..clip.x + clip.width {
+                    surface.set(x as u16, y as u16, color);
                 }
             }
-            DisplayEvent::Matrix {
-                mut matrix,
-                target_x,
-                target_y,
-                color,
-            } => {
-                for yi in 0..8 {
-                    for xi in 0..8 {
-                        if matrix & 1 == 0 {
-                            matrix >>= 1;
-                            continue;
-                        }
-                        let pixel = pixel(frame, target_x + xi, target_y + yi);
-                        set_color(pixel, color);
-                        matrix >>= 1;
+        }
+        DisplayEvent::Blit {
+            target_x,
+            target_y,
+            size_x,
+            pixels,
+            ..
+        } => {
+            let area = bounds.unwrap();
+            for y in area.y..area.y + area.height {
+                for x in area.x..area.x + area.width {
+                    let idx = (y - target_y as i32) as usize * size_x as usize
+                        + (x - target_x as i32) as usize;
+                    if let Some(color) = pixels[idx] {
+                        surface.set(x as u16, y as u16, color);
                     }
                 }
             }
         }
+        DisplayEvent::Matrix {
+            matrix,
+            target_x,
+            target_y,
+            color,
+        } => {
+            for i in 0..64 {
+                if matrix & (1 << i) != 0 {
+                    let (x, y) = (target_x as i32 + i % 8, target_y as i32 + i / 8);
+                    plot(surface, clip, x, y, color);
+                }
+            }
+        }
+        DisplayEvent::Line { from, to, color } => line(surface, clip, from, to, color),
+        DisplayEvent::Ellipse {
+            center,
+            radius_x,
+            radius_y,
+            filled,
+            color,
+        } => ellipse(surface, clip, center, (radius_x, radius_y), filled, color),
+        DisplayEvent::Polygon { points, color } => polygon(surface, clip, &points, color),
+        DisplayEvent::Scanout { .. }
+        | DisplayEvent::Snapshot { .. }
+        | DisplayEvent::Restore { .. }
+        | DisplayEvent::DoubleBuffer { .. }
+        | DisplayEvent::Present
+        | DisplayEvent::Clip { .. }
+        | DisplayEvent::Indexed { .. }
+        | DisplayEvent::Palette { .. } => {}
+    }
+}
+
+fn plot<S: Surface + ?Sized>(surface: &mut S, clip: Rect, x: i32, y: i32, color: u32) {
+    if clip.contains(x, y) {
+        surface.set(x as u16, y as u16, color);
+    }
+}
+
+/// Horizontal line from `x0` to `x1` inclusive.
+fn span<S: Surface + ?Sized>(surface: &mut S, clip: Rect, x0: i32, x1: i32, y: i32, color: u32) {
+    if !(clip.y..clip.y + clip.height).contains(&y) {
+        return;
+    }
+    for x in x0.max(clip.x)..=x1.min(clip.x + clip.width - 1) {
+        surface.set(x as u16, y as u16, color);
+    }
+}
+
+/// Bresenham's line algorithm.
+fn line<S: Surface + ?Sized>(
+    surface: &mut S,
+    clip: Rect,
+    (mut x, mut y): (i32, i32),
+    to: (i32, i32),
+    color: u32,
+) {
+    let dx = (to.0 - x).abs();
+    let dy = -(to.1 - y).abs();
+    let step_x = if x < to.0 { 1 } else { -1 };
+    let step_y = if y < to.1 { 1 } else { -1 };
+    let mut error = dx + dy;
+    loop {
+        plot(surface, clip, x, y, color);
+        if (x, y) == to {
+            break;
+        }
+        // Both steps are decided on the error before either of them
+        let error2 = 2 * error;
+        if error2 >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if error2 <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+}
+
+/// Midpoint ellipse algorithm, filled ellipses are drawn as spans between the outline points.
+fn ellipse<S: Surface + ?Sized>(
+    surface: &mut S,
+    clip: Rect,
+    (cx, cy): (i32, i32),
+    (rx, ry): (i32, i32),
+    filled: bool,
+    color: u32,
+) {
+    // Keeps the error terms in range
+    let (rx, ry) = (rx.clamp(0, i16::MAX.into()), ry.clamp(0, i16::MAX.into()));
+    if ry == 0 {
+        return span(surface, clip, cx - rx, cx + rx, cy, color);
+    }
+
+    let mut draw = |x: i32, y: i32| {
+        for y in [cy - y, cy + y] {
+            if filled {
+                span(surface, clip, cx - x, cx + x, y, color);
+            } else {
+                plot(surface, clip, cx - x, y, color);
+                plot(surface, clip, cx + x, y, color);
+            }
+        }
+    };
+
+    // Points of one quadrant, the others are mirrored
+    let (rx2, ry2) = (rx as i64 * rx as i64, ry as i64 * ry as i64);
+    let (mut x, mut y) = (0i64, ry as i64);
+    let (mut px, mut py) = (0, 2 * rx2 * y);
+    // Where the slope is above -1, step x
+    let mut p = ry2 - rx2 * ry as i64 + rx2 / 4;
+    while px < py {
+        draw(x as i32, y as i32);
+        x += 1;
+        px += 2 * ry2;
+        if p < 0 {
+            p += ry2 + px;
+        } else {
+            y -= 1;
+            py -= 2 * rx2;
+            p += ry2 + px - py;
+        }
+    }
+    // Then step y
+    p = ry2 * (2 * x + 1) * (2 * x + 1) / 4 + rx2 * (y - 1) * (y - 1) - rx2 * ry2;
+    while y >= 0 {
+        draw(x as i32, y as i32);
+        y -= 1;
+        py -= 2 * rx2;
+        if p > 0 {
+            p += rx2 - py;
+        } else {
+            x += 1;
+            px += 2 * ry2;
+            p += rx2 - py + px;
+        }
+    }
+}
+
+/// Scanline fill, a pixel is inside if its center is.
+fn polygon<S: Surface + ?Sized>(surface: &mut S, clip: Rect, points: &[(i32, i32)], color: u32) {
+    let area = bounding_box(points).intersect(&clip);
+    let mut crossings = vec![];
+    for y in area.y..area.y + area.height {
+        let center = y as f64 + 0.5;
+        crossings.clear();
+        for (i, &(x0, y0)) in points.iter().enumerate() {
+            let (x1, y1) = points[(i + 1) % points.len()];
+            let (low, high) = (y0.min(y1) as f64, y0.max(y1) as f64);
+            // Half open so a vertex shared by two edges is only counted once
+            if center >= low && center < high {
+                let t = (center - y0 as f64) / (y1 - y0) as f64;
+                crossings.push(x0 as f64 + t * (x1 - x0) as f64);
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        for pair in crossings.chunks_exact(2) {
+            // First and last pixel with the center inside
+            let start = (pair[0] - 0.5).ceil() as i32;
+            let end = (pair[1] - 0.5).ceil() as i32 - 1;
+            span(surface, clip, start, end, y, color);
+        }
     }
 }
 
-pub fn pixel(frame: &mut [u8], x: u16, y: u16) -> &mut [u8; 4] {
-    let (x, y) = (x as usize, y as usize);
-    let idx = (y * WIDTH as usize + x) * 4;
-    (&mut frame[idx..idx + 4]).try_into().unwrap()
+/// Copy a rectangle within the surface, the source and target may overlap.
+///
+/// The rectangle is clipped so that both the source and the target are on screen,
+/// only target pixels inside `clip` are written.
+fn copy<S: Surface + ?Sized>(
+    surface: &mut S,
+    clip: Rect,
+    source: (u16, u16),
+    target: (u16, u16),
+    size: (u16, u16),
+) {
+    let Resolution { width, height } = surface.resolution();
+    let size_x = size.0.min(width.saturating_sub(source.0.max(target.0)));
+    let size_y = size.1.min(height.saturating_sub(source.1.max(target.1)));
+    let mut row = Vec::with_capacity(size_x as usize);
+    let mut copy_row = |yi| {
+        // Read the whole row first, in case the source and target overlap on it
+        row.clear();
+        row.extend((0..size_x).map(|xi| surface.get(source.0 + xi, source.1 + yi)));
+        for (xi, &color) in (0..).zip(&row) {
+            let (x, y) = (target.0 + xi, target.1 + yi);
+            if clip.contains(x as i32, y as i32) {
+                surface.set(x, y, color);
+            }
+        }
+    };
+    // Copy the rows in an order that doesn't overwrite source rows before they are copied
+    if target.1 > source.1 {
+        (0..size_y).rev().for_each(&mut copy_row);
+    } else {
+        (0..size_y).for_each(&mut copy_row);
+    }
 }
 
 pub fn set_color(pixel: &mut [u8; 4], color: u32) {
@@ -154,3 +820,282 @@ pub fn set_color(pixel: &mut [u8; 4], color: u32) {
     pixel[2] = color as u8;
     pixel[3] = 255;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small surface of 0xRRGGBB colors
+    struct Grid {
+        resolution: Resolution,
+        pixels: Vec<u32>,
+    }
+
+    impl Grid {
+        fn new(width: u16, height: u16) -> Self {
+            let resolution = Resolution { width, height };
+            Self {
+                resolution,
+                pixels: vec![0; resolution.pixels()],
+            }
+        }
+
+        /// Every pixel a different color.
+        fn numbered(width: u16, height: u16) -> Self {
+            let mut grid = Self::new(width, height);
+            for (i, pixel) in grid.pixels.iter_mut().enumerate() {
+                *pixel = i as u32 + 1;
+            }
+            grid
+        }
+
+        /// One string per row, `#` for drawn pixels and `.` for the rest.
+        fn rows(&self) -> Vec<String> {
+            self.pixels
+                .chunks(self.resolution.width as usize)
+                .map(|row| {
+                    row.iter()
+                        .map(|&c| if c == 0 { '.' } else { '#' })
+                        .collect()
+                })
+                .collect()
+        }
+    }
+
+    impl Surface for Grid {
+        fn resolution(&self) -> Resolution {
+            self.resolution
+        }
+
+        fn get(&self, x: u16, y: u16) -> u32 {
+            self.pixels[self.resolution.index(x, y)]
+        }
+
+        fn set(&mut self, x: u16, y: u16, color: u32) {
+            let idx = self.resolution.index(x, y);
+            self.pixels[idx] = color;
+        }
+    }
+
+    /// Copy on a numbered 8x8 grid and compare against copying from an untouched grid.
+    fn check_copy(source: (u16, u16), target: (u16, u16), size: (u16, u16), clip: Rect) {
+        let original = Grid::numbered(8, 8);
+        let mut expected = Grid::numbered(8, 8);
+        for yi in 0..size.1.min(8) {
+            for xi in 0..size.0.min(8) {
+                let (sx, sy) = (source.0 + xi, source.1 + yi);
+                let (tx, ty) = (target.0 + xi, target.1 + yi);
+                if sx < 8 && sy < 8 && tx < 8 && ty < 8 && clip.contains(tx.into(), ty.into()) {
+                    expected.set(tx, ty, original.get(sx, sy));
+                }
+            }
+        }
+
+        let mut grid = Grid::numbered(8, 8);
+        draw(
+            &mut grid,
+            clip,
+            DisplayEvent::Copy {
+                target_x: target.0,
+                target_y: target.1,
+                source_x: source.0,
+                source_y: source.1,
+                size_x: size.0,
+                size_y: size.1,
+            },
+        );
+        assert_eq!(
+            grid.pixels, expected.pixels,
+            "copy {size:?} from {source:?} to {target:?}"
+        );
+    }
+
+    #[test]
+    fn copy_overlapping() {
+        let screen = Rect::new(0, 0, 8, 8);
+        for target in [(3, 2), (1, 2), (2, 3), (2, 1), (3, 3), (1, 1)] {
+            check_copy((2, 2), target, (4, 4), screen);
+        }
+        check_copy((2, 2), (2, 2), (4, 4), screen);
+    }
+
+    #[test]
+    fn copy_off_screen() {
+        let screen = Rect::new(0, 0, 8, 8);
+        check_copy((6, 6), (0, 0), (4, 4), screen);
+        check_copy((0, 0), (6, 5), (4, 4), screen);
+        check_copy((0, 0), (100, 0), (4, 4), screen);
+        check_copy((0, 0), (1, 1), (u16::MAX, u16::MAX), screen);
+    }
+
+    #[test]
+    fn copy_clipped() {
+        check_copy((0, 0), (2, 2), (6, 6), Rect::new(3, 1, 2, 4));
+        check_copy((0, 0), (2, 2), (6, 6), Rect::new(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn intersect() {
+        let rect = Rect::new(2, 2, 4, 4);
+        assert_eq!(
+            rect.intersect(&Rect::new(4, 0, 8, 3)),
+            Rect::new(4, 2, 2, 1)
+        );
+        assert_eq!(rect.intersect(&Rect::new(0, 0, 8, 8)), rect);
+        let off_screen = Rect {
+            x: -5,
+            y: -5,
+            width: 3,
+            height: 20,
+        };
+        let empty = rect.intersect(&off_screen);
+        assert_eq!((empty.width, empty.height), (0, 4));
+        assert!(Rect::new(0, 0, 8, 8).encloses(&rect));
+        assert!(!rect.encloses(&Rect::new(0, 0, 8, 8)));
+    }
+
+    #[test]
+    fn draw_clipped() {
+        let mut grid = Grid::new(6, 4);
+        let clip = Rect::new(1, 1, 4, 2);
+        draw(
+            &mut grid,
+            clip,
+            DisplayEvent::Rectangle {
+                target_x: 3,
+                target_y: 0,
+                size_x: u16::MAX,
+                size_y: u16::MAX,
+                color: 1,
+            },
+        );
+        draw(
+            &mut grid,
+            clip,
+            DisplayEvent::Matrix {
+                matrix: u64::MAX,
+                target_x: 0,
+                target_y: 2,
+                color: 1,
+            },
+        );
+        assert_eq!(grid.rows(), ["......", "...##.", ".####.", "......"]);
+    }
+
+    #[test]
+    fn blit_clipped() {
+        let mut grid = Grid::new(4, 4);
+        draw(
+            &mut grid,
+            Rect::new(0, 0, 4, 4),
+            DisplayEvent::Blit {
+                target_x: 2,
+                target_y: 1,
+                size_x: 3,
+                size_y: 4,
+                pixels: (0..12).map(|i| (i % 2 == 0).then_some(1)).collect(),
+            },
+        );
+        assert_eq!(grid.rows(), ["....", "..#.", "...#", "..#."]);
+    }
+
+    /// Draw on an empty 7x7 grid.
+    fn drawn(f: impl FnOnce(&mut Grid, Rect)) -> Vec<String> {
+        let mut grid = Grid::new(7, 7);
+        f(&mut grid, Rect::new(0, 0, 7, 7));
+        grid.rows()
+    }
+
+    #[test]
+    fn line_end_points() {
+        for (from, to) in [((0, 0), (6, 3)), ((6, 3), (0, 0)), ((1, 6), (4, 0))] {
+            let rows = drawn(|grid, clip| line(grid, clip, from, to, 1));
+            let count = rows
+                .iter()
+                .flat_map(|row| row.chars())
+                .filter(|&c| c == '#')
+                .count();
+            assert_eq!(count, 7, "{from:?} to {to:?}");
+            for (x, y) in [from, to] {
+                assert_eq!(&rows[y as usize][x as usize..][..1], "#");
+            }
+        }
+    }
+
+    #[test]
+    fn line_off_screen() {
+        let rows = drawn(|grid, clip| line(grid, clip, (-100, 3), (100, 3), 1));
+        assert_eq!(rows[3], "#######");
+        let rows = drawn(|grid, clip| line(grid, clip, (-100, -100), (-1, 50), 1));
+        assert!(rows.iter().all(|row| row == "......."));
+    }
+
+    #[test]
+    fn ellipse_zero_radius() {
+        let rows = drawn(|grid, clip| ellipse(grid, clip, (3, 3), (0, 0), true, 1));
+        assert_eq!(rows[3], "...#...");
+        let rows = drawn(|grid, clip| ellipse(grid, clip, (3, 3), (2, 0), false, 1));
+        assert_eq!(rows[3], ".#####.");
+        let rows = drawn(|grid, clip| ellipse(grid, clip, (3, 3), (0, 2), false, 1));
+        let column: String = rows.iter().map(|row| &row[3..4]).collect();
+        assert_eq!(column, ".#####.");
+    }
+
+    #[test]
+    fn ellipse_shapes() {
+        let outline = drawn(|grid, clip| ellipse(grid, clip, (3, 3), (3, 2), false, 1));
+        assert_eq!(
+            outline,
+            [
+                ".......", "..###..", ".#...#.", "#.....#", ".#...#.", "..###..", "......."
+            ]
+        );
+        let filled = drawn(|grid, clip| ellipse(grid, clip, (3, 3), (3, 2), true, 1));
+        assert_eq!(
+            filled,
+            [
+                ".......", "..###..", ".#####.", "#######", ".#####.", "..###..", "......."
+            ]
+        );
+        // Radii are clamped, a negative one is 0
+        let clamped = drawn(|grid, clip| ellipse(grid, clip, (3, 3), (i32::MAX, -5), true, 1));
+        assert_eq!(clamped[3], "#######");
+    }
+
+    #[test]
+    fn polygon_degenerate() {
+        let shapes: [&[(i32, i32)]; 4] = [
+            &[],
+            &[(2, 2)],
+            &[(1, 1), (1, 1), (1, 1)],
+            &[(0, 0), (3, 3), (6, 6)],
+        ];
+        for points in shapes {
+            let rows = drawn(|grid, clip| polygon(grid, clip, points, 1));
+            assert!(rows.iter().all(|row| row == "......."), "{points:?}");
+        }
+    }
+
+    #[test]
+    fn polygon_shapes() {
+        let square = drawn(|grid, clip| polygon(grid, clip, &[(1, 1), (5, 1), (5, 5), (1, 5)], 1));
+        assert_eq!(
+            square,
+            [
+                ".......", ".####..", ".####..", ".####..", ".####..", ".......", "......."
+            ]
+        );
+        // Self-intersecting, the even-odd rule leaves the crossing out
+        let bowtie = drawn(|grid, clip| polygon(grid, clip, &[(0, 0), (6, 6), (6, 0), (0, 6)], 1));
+        assert_eq!(
+            bowtie,
+            [
+                ".....#.", "#...##.", "##.###.", "##.###.", "#...##.", ".....#.", "......."
+            ]
+        );
+        let huge =
+            drawn(|grid, clip| polygon(grid, clip, &[(-100, -100), (100, -100), (100, 100)], 1));
+        assert_eq!(huge[0], "#######");
+        assert_eq!(huge[6], "......#");
+    }
+}
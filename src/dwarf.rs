@@ -0,0 +1,297 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::Result;
+use gimli::{
+    AttributeValue, DebuggingInformationEntry, DwarfSections, EndianSlice, LittleEndian, Operation,
+    Reader, Unit,
+};
+use object::{Object, ObjectSection};
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+
+/// Read the line tables and the local variables of every function.
+pub fn load(file: &object::File) -> Result<(Lines, Vec<Scope>)> {
+    let sections = DwarfSections::load(|id| -> Result<Cow<[u8]>> {
+        Ok(match file.section_by_name(id.name()) {
+            Some(section) => section.uncompressed_data()?,
+            None => Cow::Borrowed(&[]),
+        })
+    })?;
+    let dwarf = sections.borrow(|section| EndianSlice::new(section, LittleEndian));
+
+    let mut lines = Lines::default();
+    let mut scopes = vec![];
+    let mut file_indices = HashMap::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        lines.add_unit(&dwarf, &unit, &mut file_indices)?;
+        add_scopes(&dwarf, &unit, &mut scopes)?;
+    }
+    // An end of sequence goes before a sequence starting at the same address
+    lines.rows.sort_by_key(|row| (row.addr, row.line != 0));
+    Ok((lines, scopes))
+}
+
+/// Start of a run of instructions belonging to one source line
+struct Row {
+    addr: u32,
+    file: usize,
+    /// 0 for the end of a sequence, addresses after it have no line
+    line: u32,
+}
+
+/// Address to source line mapping
+#[derive(Default)]
+pub struct Lines {
+    files: Vec<String>,
+    /// Sorted by address
+    rows: Vec<Row>,
+}
+
+impl Lines {
+    fn add_unit(
+        &mut self,
+        dwarf: &gimli::Dwarf<Slice>,
+        unit: &Unit<Slice>,
+        file_indices: &mut HashMap<String, usize>,
+    ) -> Result<()> {
+        let Some(program) = unit.line_program.clone() else {
+            return Ok(());
+        };
+        let comp_dir = unit.comp_dir.map(|dir| dir.to_string_lossy().into_owned());
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            let addr = row.address() as u32;
+            if row.end_sequence() {
+                self.rows.push(Row {
+                    addr,
+                    file: 0,
+                    line: 0,
+                });
+                continue;
+            }
+            let Some(entry) = row.file(header) else {
+                continue;
+            };
+            let mut path = String::new();
+            if let Some(dir) = entry.directory(header) {
+                path = dwarf.attr_string(unit, dir)?.to_string_lossy().into_owned();
+            }
+            let name = dwarf.attr_string(unit, entry.path_name())?;
+            let name = name.to_string_lossy();
+            if name.starts_with('/') {
+                path = name.into_owned();
+            } else {
+                if !path.starts_with('/')
+                    && let Some(comp_dir) = &comp_dir
+                {
+                    path = format!("{comp_dir}/{path}");
+                }
+                path = format!("{}/{name}", path.trim_end_matches('/'));
+            }
+            let file = *file_indices.entry(path.clone()).or_insert_with(|| {
+                self.files.push(path);
+                self.files.len() - 1
+            });
+            let line = row.line().map_or(0, |line| line.get() as u32);
+            self.rows.push(Row { addr, file, line });
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Source file and line of `addr`.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = self.rows.partition_point(|row| row.addr <= addr);
+        let row = &self.rows[idx.checked_sub(1)?];
+        (row.line != 0).then(|| (self.files[row.file].as_str(), row.line))
+    }
+
+    /// Lowest address of `line` in a file whose path ends with `file`.
+    pub fn address(&self, file: &str, line: u32) -> Option<u32> {
+        self.rows
+            .iter()
+            .filter(|row| row.line == line)
+            .filter(|row| {
+                let path = &self.files[row.file];
+                path == file || path.ends_with(&format!("/{file}"))
+            })
+            .map(|row| row.addr)
+            .min()
+    }
+}
+
+/// Where a variable lives, only single operation expressions are supported
+#[derive(Clone, Copy)]
+pub enum Location {
+    Register(usize),
+    Address(u32),
+    /// Offset from the frame base of the function
+    FrameOffset(i32),
+    RegisterOffset(usize, i32),
+}
+
+/// A register, or memory at an address
+pub enum Place {
+    Register(usize),
+    Memory(u32),
+}
+
+pub struct Variable {
+    pub name: String,
+    pub location: Location,
+    /// Size of the type in bytes, if known
+    pub size: Option<u32>,
+}
+
+/// A function and its parameters and local variables, including those of nested blocks
+pub struct Scope {
+    pub name: String,
+    pub low_pc: u32,
+    pub high_pc: u32,
+    frame_base: Option<Location>,
+    pub variables: Vec<Variable>,
+}
+
+impl Scope {
+    /// Where `variable` currently is, given the register values.
+    pub fn place(&self, variable: &Variable, registers: &[u32; 32]) -> Option<Place> {
+        let offset = |reg: usize, offset: i32| registers[reg].wrapping_add(offset as u32);
+        Some(match variable.location {
+            Location::Register(reg) => Place::Register(reg),
+            Location::Address(addr) => Place::Memory(addr),
+            Location::RegisterOffset(reg, off) => Place::Memory(offset(reg, off)),
+            Location::FrameOffset(off) => match self.frame_base? {
+                Location::Register(reg) => Place::Memory(offset(reg, off)),
+                Location::RegisterOffset(reg, base) => Place::Memory(offset(reg, base + off)),
+                _ => return None,
+            },
+        })
+    }
+}
+
+fn add_scopes(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit<Slice>,
+    scopes: &mut Vec<Scope>,
+) -> Result<()> {
+    let mut entries = unit.entries();
+    let mut depth = 0;
+    // Depth of the function the entries belong to
+    let mut function_depth = None;
+    while let Some((delta, entry)) = entries.next_dfs()? {
+        depth += delta;
+        if function_depth.is_some_and(|function_depth| depth <= function_depth) {
+            function_depth = None;
+        }
+        match entry.tag() {
+            gimli::DW_TAG_subprogram => {
+                let Some(low_pc) = entry.attr_value(gimli::DW_AT_low_pc)? else {
+                    continue;
+                };
+                let Some(low_pc) = dwarf.attr_address(unit, low_pc)? else {
+                    continue;
+                };
+                let high_pc = match entry.attr_value(gimli::DW_AT_high_pc)? {
+                    Some(AttributeValue::Udata(len)) => low_pc + len,
+                    Some(value) => dwarf.attr_address(unit, value)?.unwrap_or(low_pc),
+                    None => low_pc,
+                };
+                let frame_base = match entry.attr_value(gimli::DW_AT_frame_base)? {
+                    Some(AttributeValue::Exprloc(expr)) => {
+                        let mut ops = expr.operations(unit.encoding());
+                        match (ops.next()?, ops.next()?) {
+                            // Assume the frame pointer holds the CFA, as with -fno-omit-frame-pointer
+                            (Some(Operation::CallFrameCFA), None) => Some(Location::Register(8)),
+                            (Some(op), None) => location(op),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                scopes.push(Scope {
+                    name: name(dwarf, unit, entry)?.unwrap_or_default(),
+                    low_pc: low_pc as u32,
+                    high_pc: high_pc as u32,
+                    frame_base,
+                    variables: vec![],
+                });
+                function_depth = Some(depth);
+            }
+            gimli::DW_TAG_variable | gimli::DW_TAG_formal_parameter if function_depth.is_some() => {
+                let Some(AttributeValue::Exprloc(expr)) =
+                    entry.attr_value(gimli::DW_AT_location)?
+                else {
+                    continue;
+                };
+                let mut ops = expr.operations(unit.encoding());
+                let (Some(op), None) = (ops.next()?, ops.next()?) else {
+                    continue;
+                };
+                let (Some(location), Some(name)) = (location(op), name(dwarf, unit, entry)?) else {
+                    continue;
+                };
+                let size = type_size(unit, entry)?;
+                scopes.last_mut().unwrap().variables.push(Variable {
+                    name,
+                    location,
+                    size,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn location<R: Reader>(op: Operation<R>) -> Option<Location> {
+    Some(match op {
+        Operation::Register { register } => Location::Register(register.0 as usize),
+        Operation::Address { address } => Location::Address(address as u32),
+        Operation::FrameOffset { offset } => Location::FrameOffset(offset as i32),
+        Operation::RegisterOffset {
+            register, offset, ..
+        } => Location::RegisterOffset(register.0 as usize, offset as i32),
+        _ => return None,
+    })
+    .filter(|location| match location {
+        Location::Register(reg) | Location::RegisterOffset(reg, _) => *reg < 32,
+        _ => true,
+    })
+}
+
+fn name(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit<Slice>,
+    entry: &DebuggingInformationEntry<Slice>,
+) -> Result<Option<String>> {
+    let Some(value) = entry.attr_value(gimli::DW_AT_name)? else {
+        return Ok(None);
+    };
+    let name = dwarf.attr_string(unit, value)?;
+    Ok(Some(name.to_string_lossy().into_owned()))
+}
+
+/// Byte size of the type of `entry`, following typedefs and qualifiers.
+fn type_size(unit: &Unit<Slice>, entry: &DebuggingInformationEntry<Slice>) -> Result<Option<u32>> {
+    let mut ty = entry.attr_value(gimli::DW_AT_type)?;
+    // Bounded in case of broken debug info
+    for _ in 0..16 {
+        let Some(AttributeValue::UnitRef(offset)) = ty else {
+            break;
+        };
+        let entry = unit.entry(offset)?;
+        if let Some(size) = entry
+            .attr_value(gimli::DW_AT_byte_size)?
+            .and_then(|size| size.udata_value())
+        {
+            return Ok(Some(size as u32));
+        }
+        ty = entry.attr_value(gimli::DW_AT_type)?;
+    }
+    Ok(None)
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, bail};
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+
+use crate::dwarf::{self, Lines, Scope};
+
+/// A program loaded from an ELF file
+pub struct Elf {
+    /// Loadable segments placed at their addresses, starting from address 0
+    pub image: Vec<u8>,
+    pub entry: u32,
+    pub symbols: Symbols,
+    /// Why the debug info couldn't be read, the program loads without it
+    pub warning: Option<String>,
+}
+
+impl Elf {
+    /// Parse an ELF file for a machine with `ram_size` bytes of memory.
+    pub fn parse(data: &[u8], ram_size: usize) -> Result<Self> {
+        let file = object::File::parse(data).context("[elf] failed to parse")?;
+        if file.is_64() {
+            bail!("[elf] only 32 bit files are supported");
+        }
+
+        let mut image = vec![];
+        for segment in file.segments() {
+            let addr = segment.address();
+            let data = segment.data().context("[elf] failed to read segment")?;
+            if data.len() as u64 > segment.size() {
+                bail!("[elf] segment at 0x{addr:08x} has more file data than memory size");
+            }
+            let end = addr
+                .checked_add(segment.size())
+                .filter(|&end| end <= ram_size as u64)
+                .with_context(|| format!("[elf] segment at 0x{addr:08x} doesn't fit in memory"))?
+                as usize;
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+            let addr = addr as usize;
+            image[addr..addr + data.len()].copy_from_slice(data);
+        }
+
+        let mut symbols = Symbols::new(&file);
+        let mut warning = None;
+        match dwarf::load(&file) {
+            Ok((lines, scopes)) => {
+                symbols.lines = lines;
+                symbols.scopes = scopes;
+            }
+            Err(err) => warning = Some(format!("[elf] ignoring debug info: {err:#}")),
+        }
+
+        Ok(Self {
+            image,
+            entry: file.entry() as u32,
+            symbols,
+            warning,
+        })
+    }
+
+    /// Whether `data` looks like an ELF file rather than a raw image.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(b"\x7fELF")
+    }
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+/// Symbol table of the loaded program
+#[derive(Default)]
+pub struct Symbols {
+    /// Function symbols sorted by address
+    functions: Vec<Symbol>,
+    /// Addresses of all named symbols, e.g. linker script symbols
+    addresses: HashMap<String, u32>,
+    /// Source lines from the DWARF line tables, empty without debug info
+    pub lines: Lines,
+    /// Functions with their local variables from the DWARF debug info
+    pub scopes: Vec<Scope>,
+}
+
+impl Symbols {
+    /// Symbols without the debug info, see [`dwarf::load`].
+    fn new(file: &object::File) -> Self {
+        let mut functions = vec![];
+        let mut addresses = HashMap::new();
+        for symbol in file.symbols() {
+            let Ok(name) = symbol.name() else {
+                continue;
+            };
+            if name.is_empty() {
+                continue;
+            }
+            addresses.insert(name.to_string(), symbol.address() as u32);
+            if symbol.kind() == SymbolKind::Text {
+                functions.push(Symbol {
+                    name: name.to_string(),
+                    addr: symbol.address() as u32,
+                    size: symbol.size() as u32,
+                });
+            }
+        }
+        functions.sort_by_key(|symbol| symbol.addr);
+        Self {
+            functions,
+            addresses,
+            ..Self::default()
+        }
+    }
+
+    /// Function symbols sorted by address.
+    pub fn functions(&self) -> &[Symbol] {
+        &self.functions
+    }
+
+    /// Address of the symbol called `name`.
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.addresses.get(name).copied()
+    }
+
+    /// Stack region from the `_stack_start` and `_stack_end` symbols, in either order.
+    pub fn stack_region(&self) -> Option<(u32, u32)> {
+        let start = self.get("_stack_start")?;
+        let end = self.get("_stack_end")?;
+        Some((start.min(end), start.max(end)))
+    }
+
+    /// The function containing `addr`.
+    pub fn lookup(&self, addr: u32) -> Option<&Symbol> {
+        let idx = self.functions.partition_point(|symbol| symbol.addr <= addr);
+        let symbol = &self.functions[idx.checked_sub(1)?];
+        // Symbols without a size extend to the next one
+        if symbol.size == 0 || addr < symbol.addr + symbol.size {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+
+    /// Format an address as `function+offset`, or as hex without a symbol.
+    pub fn format(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some(symbol) if symbol.addr == addr => symbol.name.clone(),
+            Some(symbol) => format!("{}+0x{:x}", symbol.name, addr - symbol.addr),
+            None => format!("0x{addr:08x}"),
+        }
+    }
+}
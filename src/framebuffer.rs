@@ -0,0 +1,263 @@
+use std::sync::{Arc, Mutex, mpsc::Sender};
+
+use anyhow::{Result, bail};
+
+use crate::{
+    cpu_thread::memory::{MemAccessSize, Memory},
+    device_log::DeviceLog,
+    display::{self, DisplayEvent, Rect, Resolution, Surface},
+};
+
+/// Pixel formats of the linear framebuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 32 bits, 0x00RRGGBB
+    Xrgb8888,
+    /// 16 bits, 5 bits red, 6 bits green, 5 bits blue
+    Rgb565,
+    /// 8 bit palette indices
+    Indexed8,
+}
+
+impl PixelFormat {
+    /// Format written to the DDI, 0 turns the framebuffer off.
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Xrgb8888),
+            2 => Some(Self::Rgb565),
+            3 => Some(Self::Indexed8),
+            _ => None,
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Self::Xrgb8888 => 1,
+            Self::Rgb565 => 2,
+            Self::Indexed8 => 3,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            Self::Xrgb8888 => 4,
+            Self::Rgb565 => 2,
+            Self::Indexed8 => 1,
+        }
+    }
+
+    fn access_size(self) -> MemAccessSize {
+        match self {
+            Self::Xrgb8888 => MemAccessSize::Word,
+            Self::Rgb565 => MemAccessSize::HalfWord,
+            Self::Indexed8 => MemAccessSize::Byte,
+        }
+    }
+
+    /// Convert a pixel to 0xRRGGBB, or to a palette index.
+    fn decode(self, pixel: u32) -> u32 {
+        match self {
+            Self::Xrgb8888 => pixel & 0xFFFFFF,
+            Self::Rgb565 => {
+                let r = (pixel >> 11) & 0x1F;
+                let g = (pixel >> 5) & 0x3F;
+                let b = pixel & 0x1F;
+                ((r << 3 | r >> 2) << 16) | ((g << 2 | g >> 4) << 8) | (b << 3 | b >> 2)
+            }
+            Self::Indexed8 => pixel & 0xFF,
+        }
+    }
+
+    /// Convert 0xRRGGBB, or a palette index, to a pixel.
+    fn encode(self, color: u32) -> u32 {
+        match self {
+            Self::Xrgb8888 => color & 0xFFFFFF,
+            Self::Rgb565 => {
+                ((color >> 8) & 0xF800) | ((color >> 5) & 0x07E0) | ((color >> 3) & 0x001F)
+            }
+            Self::Indexed8 => color & 0xFF,
+        }
+    }
+}
+
+/// A region of RAM the display scans out every frame
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    pub base: u32,
+    pub format: PixelFormat,
+    pub resolution: Resolution,
+}
+
+impl Framebuffer {
+    /// Size in bytes.
+    pub fn size(&self) -> u32 {
+        self.resolution.pixels() as u32 * self.format.bytes_per_pixel()
+    }
+
+    /// Check that the framebuffer lies in RAM.
+    pub fn check(&self, mem: &Memory) -> Result<()> {
+        if self.base as u64 + self.size() as u64 > mem.vec.len() as u64 {
+            bail!(
+                "[ddi] framebuffer at 0x{:08x} of {} bytes doesn't fit in RAM",
+                self.base,
+                self.size()
+            );
+        }
+        Ok(())
+    }
+
+    /// The framebuffer as a surface the drawing commands work on.
+    pub fn surface<'a>(&self, mem: &'a mut Memory) -> MemorySurface<'a> {
+        MemorySurface {
+            framebuffer: *self,
+            mem,
+        }
+    }
+
+    /// Copy of the pixels, for the display to scan out.
+    pub fn read(&self, mem: &Memory) -> Vec<u8> {
+        mem.vec[self.base as usize..(self.base + self.size()) as usize].to_vec()
+    }
+}
+
+/// Convert framebuffer pixels to the RGBA frame of the display.
+pub fn scan_out(format: PixelFormat, data: &[u8], palette: &[u32; 256], frame: &mut [u8]) {
+    let bytes = format.bytes_per_pixel() as usize;
+    for (pixel, rgba) in data.chunks_exact(bytes).zip(frame.chunks_exact_mut(4)) {
+        let mut word = [0; 4];
+        word[..bytes].copy_from_slice(pixel);
+        let mut color = format.decode(u32::from_le_bytes(word));
+        if format == PixelFormat::Indexed8 {
+            color = palette[color as usize];
+        }
+        display::set_color(rgba.try_into().unwrap(), color);
+    }
+}
+
+/// Drawing commands applied to the framebuffer in RAM
+pub struct MemorySurface<'a> {
+    framebuffer: Framebuffer,
+    mem: &'a mut Memory,
+}
+
+impl MemorySurface<'_> {
+    /// Address of a pixel, `None` if it is off screen.
+    fn addr(&self, x: u16, y: u16) -> Option<u32> {
+        let Framebuffer {
+            base,
+            format,
+            resolution,
+        } = self.framebuffer;
+        if x >= resolution.width || y >= resolution.height {
+            return None;
+        }
+        Some(base + resolution.index(x, y) as u32 * format.bytes_per_pixel())
+    }
+}
+
+impl Surface for MemorySurface<'_> {
+    fn resolution(&self) -> Resolution {
+        self.framebuffer.resolution
+    }
+
+    fn get(&self, x: u16, y: u16) -> u32 {
+        let format = self.framebuffer.format;
+        self.addr(x, y)
+            .and_then(|addr| self.mem.read(addr, format.access_size()).ok())
+            .map_or(0, |pixel| format.decode(pixel))
+    }
+
+    fn set(&mut self, x: u16, y: u16, color: u32) {
+        let format = self.framebuffer.format;
+        if let Some(addr) = self.addr(x, y) {
+            // The framebuffer was checked to lie in RAM
+            let _ = self
+                .mem
+                .write(addr, format.access_size(), format.encode(color));
+        }
+    }
+}
+
+/// Settings shared by the devices that draw
+#[derive(Clone, Copy)]
+struct Settings {
+    framebuffer: Option<Framebuffer>,
+    clip: Rect,
+}
+
+/// Where drawing commands go, the display or the framebuffer in RAM when it is enabled.
+///
+/// Shared by the devices that draw, so text and DDI commands end up in the same place.
+#[derive(Clone)]
+pub struct DrawTarget {
+    send: Sender<DisplayEvent>,
+    resolution: Resolution,
+    settings: Arc<Mutex<Settings>>,
+    log: DeviceLog,
+}
+
+impl DrawTarget {
+    pub fn new(send: Sender<DisplayEvent>, log: DeviceLog, resolution: Resolution) -> Self {
+        Self {
+            send,
+            resolution,
+            settings: Arc::new(Mutex::new(Settings {
+                framebuffer: None,
+                clip: resolution.rect(),
+            })),
+            log,
+        }
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        self.settings.lock().unwrap().framebuffer
+    }
+
+    pub fn set_framebuffer(&self, framebuffer: Option<Framebuffer>) {
+        self.settings.lock().unwrap().framebuffer = framebuffer;
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.settings.lock().unwrap().clip
+    }
+
+    /// Restrict drawing to `rect`.
+    pub fn set_clip(&self, rect: Rect) {
+        self.settings.lock().unwrap().clip = rect;
+        self.send(DisplayEvent::Clip { rect });
+    }
+
+    /// Send an event to the display.
+    pub fn send(&self, event: DisplayEvent) {
+        self.send.send(event).unwrap();
+    }
+
+    /// Warn if drawing at `bounds` doesn't lie completely on screen.
+    pub fn check_bounds(&self, bounds: Rect) {
+        if !self.resolution.rect().encloses(&bounds) {
+            self.log.warn(
+                "draw off screen",
+                format!(
+                    "{}x{} at ({}, {})",
+                    bounds.width, bounds.height, bounds.x, bounds.y
+                ),
+            );
+        }
+    }
+
+    /// Apply a drawing command, parts off screen are dropped with a warning.
+    pub fn draw(&self, mem: &mut Memory, event: DisplayEvent) {
+        if let Some(bounds) = event.bounds() {
+            self.check_bounds(bounds);
+        }
+        let Settings { framebuffer, clip } = *self.settings.lock().unwrap();
+        match framebuffer {
+            Some(framebuffer) => display::draw(&mut framebuffer.surface(mem), clip, event),
+            None => self.send(event),
+        }
+    }
+}
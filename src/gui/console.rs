@@ -0,0 +1,768 @@
+use std::{collections::VecDeque, path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Style, Stylize},
+    widgets::Widget,
+};
+
+use super::{Debugger, Gui, parse_value};
+use crate::{
+    cpu_thread::{
+        CpuHandle,
+        coverage::Coverage,
+        cpu::{Cpu, Stack, register_index},
+        disassembler::disassemble,
+        memory::MemAccessSize,
+        profiler::Profiler,
+        reverse::{DEFAULT_RECORD_BUDGET, Recorder, budget_from_mib, reverse_continue, step_back},
+        source,
+        stats::{Statistics, StatsHook},
+        trace::Tracer,
+    },
+    elf::Elf,
+    savestate,
+};
+
+/// Usage and description of every command, also used for tab completion
+const HELP: &[(&str, &str)] = &[
+    ("help", "show this list"),
+    (
+        "break [addr]",
+        "set a breakpoint at an address, symbol or file:line",
+    ),
+    ("delete [addr]", "delete a breakpoint, or all of them"),
+    ("step [n]", "execute n instructions"),
+    ("step line", "step to the next source line, entering calls"),
+    ("next", "step to the next source line, over calls"),
+    ("finish", "run until the current function returns"),
+    ("locals", "show the local variables of the function"),
+    ("continue", "resume the cpu"),
+    ("pause", "pause the cpu"),
+    ("x/NFU addr", "examine memory, F: x d u c i, U: b h w"),
+    ("set <reg|pc> <value>", "write a register or the pc"),
+    ("load <file>", "flash an image or ELF file and reset"),
+    ("reset", "reset the cpu and reflash the image"),
+    ("heap", "list live heap allocations"),
+    (
+        "log [clear]",
+        "show device warnings, e.g. drawing off screen",
+    ),
+    (
+        "stack [start end|off]",
+        "show the stack usage, or set the stack region",
+    ),
+    ("trace on [file]", "start tracing, to a new file if given"),
+    ("trace off", "stop tracing"),
+    (
+        "watch [addr]",
+        "stop after stores to addr, or list watchpoints",
+    ),
+    ("unwatch [addr]", "delete a watchpoint, or all of them"),
+    ("record on [MiB]", "record execution to allow stepping back"),
+    ("record off", "stop recording"),
+    ("rstep [n]", "step n instructions backwards"),
+    (
+        "rcontinue",
+        "run backwards to a breakpoint or watched store",
+    ),
+    (
+        "profile on [prefix]",
+        "start profiling, reports go to prefix.txt/.folded",
+    ),
+    ("profile save", "write the profile reports now"),
+    ("profile off", "stop profiling"),
+    (
+        "coverage on [prefix]",
+        "record coverage, reports go to prefix.info/.txt",
+    ),
+    ("coverage save", "write the coverage reports now"),
+    ("coverage off", "stop recording coverage"),
+    (
+        "stats on [file]",
+        "count instructions and device accesses, tab shows them",
+    ),
+    ("stats save <file>", "write the statistics as JSON now"),
+    ("stats off", "stop counting"),
+    ("savestate <slot|file>", "save the whole machine"),
+    ("loadstate <slot|file>", "load a saved machine"),
+];
+
+/// Command line at the bottom of the GUI
+#[derive(Default)]
+pub struct Console {
+    /// Whether key presses go to the console
+    pub focused: bool,
+    input: String,
+    history: Vec<String>,
+    /// Position in `history` while browsing it with the arrow keys
+    history_pos: usize,
+    lines: VecDeque<String>,
+}
+
+impl Console {
+    pub fn print(&mut self, line: impl Into<String>) {
+        self.lines.push_back(line.into());
+        while self.lines.len() > 100 {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.input.push(c);
+    }
+
+    pub fn pop(&mut self) {
+        self.input.pop();
+    }
+
+    /// Take the current input and add it to the history.
+    pub fn submit(&mut self) -> String {
+        let line = std::mem::take(&mut self.input);
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        self.history_pos = self.history.len();
+        line
+    }
+
+    pub fn history_prev(&mut self) {
+        if self.history_pos > 0 {
+            self.history_pos -= 1;
+            self.input = self.history[self.history_pos].clone();
+        }
+    }
+
+    pub fn history_next(&mut self) {
+        if self.history_pos < self.history.len() {
+            self.history_pos += 1;
+            self.input = self
+                .history
+                .get(self.history_pos)
+                .cloned()
+                .unwrap_or_default();
+        }
+    }
+
+    /// Complete the command name being typed.
+    pub fn complete(&mut self) {
+        if self.input.contains(' ') {
+            return;
+        }
+        let mut candidates: Vec<_> = HELP
+            .iter()
+            .map(|(usage, _)| usage.split([' ', '/']).next().unwrap())
+            .filter(|c| c.starts_with(self.input.as_str()))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        match candidates[..] {
+            [] => {}
+            [command] => self.input = format!("{command} "),
+            _ => self.print(
+                candidates
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join("  "),
+            ),
+        }
+    }
+}
+
+impl Widget for &Console {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        if area.height == 0 {
+            return;
+        }
+        let output_height = area.height as usize - 1;
+        let skip = self.lines.len().saturating_sub(output_height);
+        for (i, line) in self.lines.iter().skip(skip).enumerate() {
+            buf.set_stringn(
+                area.x,
+                area.y + i as u16,
+                line,
+                area.width as usize,
+                Style::default(),
+            );
+        }
+
+        let prompt = format!("> {}", self.input);
+        let style = if self.focused {
+            Style::default().bold()
+        } else {
+            Style::default().dim()
+        };
+        // Keep the end of long input visible
+        let skip = prompt.chars().count().saturating_sub(area.width as usize);
+        let visible: String = prompt.chars().skip(skip).collect();
+        buf.set_string(area.x, area.y + area.height - 1, visible, style);
+    }
+}
+
+/// Run a command line and print its output to the console.
+pub fn execute(gui: &Gui, debugger: &mut Debugger, line: &str) {
+    debugger.console.print(format!("> {line}"));
+    if let Err(err) = run_command(gui, debugger, line) {
+        debugger.console.print(format!("error: {err:#}"));
+    }
+}
+
+fn run_command(gui: &Gui, debugger: &mut Debugger, line: &str) -> Result<()> {
+    let mut args = line.split_whitespace();
+    let Some(command) = args.next() else {
+        return Ok(());
+    };
+    let args: Vec<_> = args.collect();
+    let mut cpu_handle = gui.cpu_handle.lock().unwrap();
+
+    match command {
+        "help" | "h" => {
+            for (usage, description) in HELP {
+                debugger.console.print(format!("{usage:<21} {description}"));
+            }
+        }
+        "break" | "b" => match args[..] {
+            [] => {
+                let mut breakpoints: Vec<_> =
+                    cpu_handle.with_cpu(|cpu| cpu.breakpoints.iter().copied().collect())?;
+                breakpoints.sort();
+                if breakpoints.is_empty() {
+                    debugger.console.print("No breakpoints");
+                }
+                for addr in breakpoints {
+                    debugger.console.print(format!("0x{addr:08X}"));
+                }
+            }
+            [addr] => {
+                let addr = cpu_handle.with_cpu(|cpu| {
+                    let addr = operand(cpu, addr)?;
+                    cpu.breakpoints.insert(addr);
+                    anyhow::Ok(addr)
+                })??;
+                debugger
+                    .console
+                    .print(format!("Breakpoint at 0x{addr:08X}"));
+            }
+            _ => bail!("usage: break [addr]"),
+        },
+        "delete" | "d" => match args[..] {
+            [] => cpu_handle.with_cpu(|cpu| cpu.breakpoints.clear())?,
+            [addr] => cpu_handle.with_cpu(|cpu| {
+                let addr = operand(cpu, addr)?;
+                if !cpu.breakpoints.remove(&addr) {
+                    bail!("no breakpoint at 0x{addr:08X}");
+                }
+                Ok(())
+            })??,
+            _ => bail!("usage: delete [addr]"),
+        },
+        "step" | "s" if args == ["line"] => {
+            source_step(debugger, &mut cpu_handle, source::step_line)?
+        }
+        "next" | "n" => source_step(debugger, &mut cpu_handle, source::next_line)?,
+        "finish" | "fin" => {
+            source_step(debugger, &mut cpu_handle, source::finish)?;
+            let a0 = cpu_handle.get_state().registers[10];
+            debugger
+                .console
+                .print(format!("a0 = 0x{a0:08x} {}", a0 as i32));
+        }
+        "locals" => {
+            let lines = cpu_handle.with_cpu(|cpu| source::locals(cpu))?;
+            if lines.is_empty() {
+                debugger.console.print("No locals");
+            }
+            for line in lines {
+                debugger.console.print(line);
+            }
+        }
+        "step" | "s" => {
+            let count = count(&args).context("usage: step [n]")?;
+            if cpu_handle.is_running() {
+                cpu_handle.stop()?;
+            }
+            debugger.previous = cpu_handle.get_state();
+            for _ in 0..count {
+                if let Err(err) = cpu_handle.step() {
+                    debugger.fault(&mut cpu_handle, err);
+                    return Ok(());
+                }
+            }
+            let pc = cpu_handle.get_state().pc;
+            debugger.console.print(format!("pc = 0x{pc:08X}"));
+        }
+        "continue" | "c" => {
+            debugger.previous = cpu_handle.get_state();
+            cpu_handle.start();
+        }
+        "pause" | "p" => cpu_handle.stop()?,
+        "set" => match args[..] {
+            [target, value] => {
+                let value = parse_value(value).context("invalid value")?;
+                if target == "pc" {
+                    cpu_handle.with_cpu(|cpu| cpu.pc = value)?;
+                } else {
+                    let reg = register_index(target).context("unknown register")?;
+                    cpu_handle.with_cpu(|cpu| cpu.write_register(reg, value))?;
+                }
+            }
+            _ => bail!("usage: set <reg|pc> <value>"),
+        },
+        "load" => match args[..] {
+            [path] => {
+                let data = std::fs::read(path).with_context(|| format!("failed to read {path}"))?;
+                let warning = cpu_handle.with_cpu(|cpu| {
+                    let mut warning = None;
+                    if Elf::is_elf(&data) {
+                        let elf = Elf::parse(&data, cpu.mem.vec.len())?;
+                        warning = elf.warning.clone();
+                        cpu.load_elf(elf)?;
+                    } else {
+                        if data.len() > cpu.mem.vec.len() {
+                            bail!(
+                                "{path} is {} bytes, larger than the {} bytes of RAM",
+                                data.len(),
+                                cpu.mem.vec.len()
+                            );
+                        }
+                        cpu.flash(&data);
+                    }
+                    cpu.reset();
+                    anyhow::Ok(warning)
+                })??;
+                if let Some(warning) = warning {
+                    debugger.console.print(format!("warning: {warning}"));
+                }
+                debugger
+                    .console
+                    .print(format!("Loaded {} bytes", data.len()));
+            }
+            _ => bail!("usage: load <file>"),
+        },
+        "reset" => cpu_handle.with_cpu(|cpu| cpu.reset())?,
+        "stack" => match args[..] {
+            [] => {
+                let state = cpu_handle.get_state();
+                let Some(stack) = state.stack else {
+                    bail!("no stack region, use stack <start> <end>");
+                };
+                debugger.console.print(format!(
+                    "Stack 0x{:08x}-0x{:08x}, {} bytes used at most",
+                    stack.start,
+                    stack.end,
+                    stack.used()
+                ));
+            }
+            ["off"] => cpu_handle.with_cpu(|cpu| cpu.stack = None)?,
+            [start, end] => {
+                let start = parse_value(start).context("invalid start")?;
+                let end = parse_value(end).context("invalid end")?;
+                if start > end {
+                    bail!("the start must be below the end");
+                }
+                cpu_handle.with_cpu(|cpu| cpu.stack = Some(Stack::new(start, end)))?;
+            }
+            _ => bail!("usage: stack [<start> <end> | off]"),
+        },
+        "heap" => {
+            let heap = gui.heap.read();
+            if heap.allocations.is_empty() {
+                debugger.console.print("No live allocations");
+            }
+            for (addr, size) in &heap.allocations {
+                debugger.console.print(format!("0x{addr:08x} {size} bytes"));
+            }
+        }
+        "log" => match args.as_slice() {
+            [] => {
+                let lines = gui.device_log.lines();
+                if lines.is_empty() {
+                    debugger.console.print("No device warnings");
+                }
+                for line in lines {
+                    debugger.console.print(line);
+                }
+            }
+            ["clear"] => gui.device_log.clear(),
+            _ => bail!("usage: log [clear]"),
+        },
+        "trace" => cpu_handle.with_cpu(|cpu| trace(cpu, &args))??,
+        "profile" => cpu_handle.with_cpu(|cpu| profile(cpu, &args))??,
+        "coverage" => cpu_handle.with_cpu(|cpu| coverage(cpu, &args))??,
+        "stats" => cpu_handle.with_cpu(|cpu| stats(gui, cpu, &args))??,
+        "savestate" => {
+            let [arg] = args[..] else {
+                bail!("usage: savestate <slot|file>");
+            };
+            let path = gui.state_path(arg);
+            cpu_handle
+                .with_cpu(|cpu| savestate::save(cpu, &gui.display, gui.resolution, &path))??;
+            debugger.console.print(format!("Saved {}", path.display()));
+        }
+        "loadstate" => {
+            let [arg] = args[..] else {
+                bail!("usage: loadstate <slot|file>");
+            };
+            let path = gui.state_path(arg);
+            cpu_handle
+                .with_cpu(|cpu| savestate::load(cpu, &gui.display, gui.resolution, &path))??;
+            debugger.console.print(format!("Loaded {}", path.display()));
+        }
+        "watch" => match args[..] {
+            [] => {
+                let mut watchpoints: Vec<_> =
+                    cpu_handle.with_cpu(|cpu| cpu.watchpoints.iter().copied().collect())?;
+                watchpoints.sort();
+                if watchpoints.is_empty() {
+                    debugger.console.print("No watchpoints");
+                }
+                for addr in watchpoints {
+                    debugger.console.print(format!("0x{addr:08X}"));
+                }
+            }
+            [addr] => {
+                let addr = cpu_handle.with_cpu(|cpu| {
+                    let addr = operand(cpu, addr)?;
+                    cpu.watchpoints.insert(addr);
+                    anyhow::Ok(addr)
+                })??;
+                debugger
+                    .console
+                    .print(format!("Watchpoint at 0x{addr:08X}"));
+            }
+            _ => bail!("usage: watch [addr]"),
+        },
+        "unwatch" => match args[..] {
+            [] => cpu_handle.with_cpu(|cpu| cpu.watchpoints.clear())?,
+            [addr] => cpu_handle.with_cpu(|cpu| {
+                let addr = operand(cpu, addr)?;
+                if !cpu.watchpoints.remove(&addr) {
+                    bail!("no watchpoint at 0x{addr:08X}");
+                }
+                Ok(())
+            })??,
+            _ => bail!("usage: unwatch [addr]"),
+        },
+        "record" => {
+            let status = cpu_handle.with_cpu(|cpu| record(cpu, &args))??;
+            debugger.console.print(status);
+        }
+        "rstep" | "rs" => {
+            let count = count(&args).context("usage: rstep [n]")?;
+            if cpu_handle.is_running() {
+                cpu_handle.stop()?;
+            }
+            debugger.previous = cpu_handle.get_state();
+            let stepped = cpu_handle.with_cpu(|cpu| {
+                let mut stepped = 0;
+                while stepped < count && step_back(cpu)? {
+                    stepped += 1;
+                }
+                anyhow::Ok(stepped)
+            })??;
+            if stepped < count {
+                debugger.console.print("Reached the start of the recording");
+            }
+            let pc = cpu_handle.get_state().pc;
+            debugger.console.print(format!("pc = 0x{pc:08X}"));
+        }
+        "rcontinue" | "rc" => {
+            if cpu_handle.is_running() {
+                cpu_handle.stop()?;
+            }
+            debugger.previous = cpu_handle.get_state();
+            if !cpu_handle.with_cpu(reverse_continue)?? {
+                debugger.console.print("Reached the start of the recording");
+            }
+            let pc = cpu_handle.get_state().pc;
+            debugger.console.print(format!("pc = 0x{pc:08X}"));
+        }
+        _ if command == "x" || command.starts_with("x/") => {
+            let [addr] = args[..] else {
+                bail!("usage: x/NFU addr");
+            };
+            let lines = cpu_handle.with_cpu(|cpu| examine(cpu, command, addr))??;
+            for line in lines {
+                debugger.console.print(line);
+            }
+        }
+        _ => bail!("unknown command: {command}, try help"),
+    }
+    Ok(())
+}
+
+/// Run a source level step on the stopped CPU and print where it stopped.
+fn source_step(
+    debugger: &mut Debugger,
+    cpu_handle: &mut CpuHandle,
+    step: fn(&mut Cpu) -> Result<bool>,
+) -> Result<()> {
+    if cpu_handle.is_running() {
+        cpu_handle.stop()?;
+    }
+    debugger.previous = cpu_handle.get_state();
+    let cpu = cpu_handle.stopped_cpu().context("the cpu is running")?;
+    let result = step(cpu);
+    cpu.publish();
+    match result {
+        Ok(true) => {}
+        Ok(false) => debugger
+            .console
+            .print("Gave up after too many instructions"),
+        Err(err) => {
+            debugger.fault(cpu_handle, err);
+            return Ok(());
+        }
+    }
+    let cpu = cpu_handle.stopped_cpu().context("the cpu is running")?;
+    let location = source::location(cpu);
+    debugger
+        .console
+        .print(format!("pc = 0x{:08X} {location}", cpu.pc));
+    Ok(())
+}
+
+/// Most instructions a single `step` or `rstep` runs, they run while the GUI waits
+const MAX_STEPS: u32 = 100_000;
+
+fn count(args: &[&str]) -> Result<u32> {
+    let count = match args {
+        [] => 1,
+        [count] => parse_value(count).context("invalid count")?,
+        _ => bail!("too many arguments"),
+    };
+    if count > MAX_STEPS {
+        bail!("at most {MAX_STEPS} steps at a time, use a breakpoint and continue instead");
+    }
+    Ok(count)
+}
+
+fn record(cpu: &mut Cpu, args: &[&str]) -> Result<String> {
+    match args {
+        [] => {}
+        ["on"] => {
+            cpu.remove_hooks::<Recorder>();
+            cpu.add_hook(Box::new(Recorder::new(DEFAULT_RECORD_BUDGET)));
+        }
+        ["on", mib] => {
+            let mib = parse_value(mib).context("invalid size")?;
+            cpu.remove_hooks::<Recorder>();
+            cpu.add_hook(Box::new(Recorder::new(budget_from_mib(mib as usize)?)));
+        }
+        ["off"] => cpu.remove_hooks::<Recorder>(),
+        _ => bail!("usage: record on [MiB] | record off"),
+    }
+    Ok(match cpu.hook_mut::<Recorder>() {
+        Some(recorder) if recorder.is_empty() => "Recording, nothing recorded yet".into(),
+        Some(recorder) => format!(
+            "Recording, {} instructions, {} device writes that can't be undone",
+            recorder.len(),
+            recorder.csr_writes().count()
+        ),
+        None => "Not recording".into(),
+    })
+}
+
+fn profile(cpu: &mut Cpu, args: &[&str]) -> Result<()> {
+    match args {
+        ["on"] => {
+            cpu.remove_hooks::<Profiler>();
+            cpu.add_hook(Box::new(Profiler::new("profile")));
+        }
+        ["on", prefix] => {
+            cpu.remove_hooks::<Profiler>();
+            cpu.add_hook(Box::new(Profiler::new(prefix)));
+        }
+        ["save"] => {
+            let symbols = Arc::clone(&cpu.symbols);
+            cpu.hook_mut::<Profiler>()
+                .context("not profiling")?
+                .write(&symbols)?;
+        }
+        ["off"] => cpu.remove_hooks::<Profiler>(),
+        _ => bail!("usage: profile on [prefix] | profile save | profile off"),
+    }
+    Ok(())
+}
+
+fn stats(gui: &Gui, cpu: &mut Cpu, args: &[&str]) -> Result<()> {
+    match args {
+        ["on"] | ["on", _] => {
+            let path = args.get(1).map(PathBuf::from);
+            cpu.remove_hooks::<StatsHook>();
+            *gui.stats.lock().unwrap() = Statistics::default();
+            cpu.add_hook(Box::new(StatsHook::new(Arc::clone(&gui.stats), path)));
+        }
+        ["save", path] => cpu
+            .hook_mut::<StatsHook>()
+            .context("statistics are off, use stats on")?
+            .write(&PathBuf::from(path))?,
+        ["off"] => cpu.remove_hooks::<StatsHook>(),
+        _ => bail!("usage: stats on [file] | stats save <file> | stats off"),
+    }
+    Ok(())
+}
+
+fn coverage(cpu: &mut Cpu, args: &[&str]) -> Result<()> {
+    match args {
+        ["on"] => {
+            cpu.remove_hooks::<Coverage>();
+            cpu.add_hook(Box::new(Coverage::new("coverage")));
+        }
+        ["on", prefix] => {
+            cpu.remove_hooks::<Coverage>();
+            cpu.add_hook(Box::new(Coverage::new(prefix)));
+        }
+        ["save"] => cpu
+            .hook::<Coverage>()
+            .context("coverage is off, use coverage on")?
+            .write(cpu)?,
+        ["off"] => cpu.remove_hooks::<Coverage>(),
+        _ => bail!("usage: coverage on [prefix] | coverage save | coverage off"),
+    }
+    Ok(())
+}
+
+fn trace(cpu: &mut Cpu, args: &[&str]) -> Result<()> {
+    match args {
+        ["on", path] => {
+            cpu.remove_hooks::<Tracer>();
+            cpu.add_hook(Box::new(Tracer::new(path)?));
+        }
+        ["on"] => {
+            cpu.hook_mut::<Tracer>()
+                .context("no trace file, use trace on <file>")?
+                .enabled = true;
+        }
+        ["off"] => {
+            if let Some(tracer) = cpu.hook_mut::<Tracer>() {
+                tracer.enabled = false;
+                tracer.flush()?;
+            }
+        }
+        _ => bail!("usage: trace on [file] | trace off"),
+    }
+    Ok(())
+}
+
+/// Parse an address or value, which may also be a register name or `pc`.
+fn operand(cpu: &Cpu, s: &str) -> Result<u32> {
+    if s == "pc" {
+        return Ok(cpu.pc);
+    }
+    if let Some(reg) = register_index(s) {
+        return Ok(cpu.read_register(reg));
+    }
+    if let Some(addr) = cpu.symbols.get(s) {
+        return Ok(addr);
+    }
+    if let Some((file, line)) = s.rsplit_once(':')
+        && let Ok(line) = line.parse()
+    {
+        return cpu
+            .symbols
+            .lines
+            .address(file, line)
+            .with_context(|| format!("no code at {s}"));
+    }
+    parse_value(s).with_context(|| format!("invalid value: {s}"))
+}
+
+/// Most units a single `x` shows, the console only keeps the last 100 lines anyway
+const MAX_EXAMINE: u32 = 1024;
+
+/// Format memory like gdb's `x/NFU addr`.
+fn examine(cpu: &Cpu, command: &str, addr: &str) -> Result<Vec<String>> {
+    let mut addr = operand(cpu, addr)?;
+    let spec = command.strip_prefix("x/").unwrap_or("");
+    let digits = spec
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(spec.len());
+    let count: u32 = if digits == 0 {
+        1
+    } else {
+        spec[..digits].parse().context("invalid count")?
+    };
+    if count > MAX_EXAMINE {
+        bail!("at most {MAX_EXAMINE} units at a time");
+    }
+
+    let mut format = 'x';
+    let mut size = MemAccessSize::Word;
+    for c in spec[digits..].chars() {
+        match c {
+            'i' => {
+                return (0..count)
+                    .map(|i| {
+                        let addr = addr.wrapping_add(4 * i);
+                        let insn = cpu.mem.read(addr, MemAccessSize::Word)?;
+                        Ok(format!("0x{addr:08X}: {}", disassemble(insn)))
+                    })
+                    .collect();
+            }
+            'x' | 'd' | 'u' | 'c' => format = c,
+            'b' => size = MemAccessSize::Byte,
+            'h' => size = MemAccessSize::HalfWord,
+            'w' => size = MemAccessSize::Word,
+            _ => bail!("invalid format: {c}"),
+        }
+    }
+
+    let bytes = size as u32;
+    let per_line = 16 / bytes;
+    let mut lines = vec![];
+    let mut line = String::new();
+    for i in 0..count {
+        if i % per_line == 0 {
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            line = format!("0x{addr:08X}:");
+        }
+        let value = cpu.mem.read(addr, size)?;
+        let value = match (format, size) {
+            ('x', _) => format!("0x{:0width$X}", value, width = 2 * bytes as usize),
+            ('d', MemAccessSize::Byte) => format!("{}", value as i8),
+            ('d', MemAccessSize::HalfWord) => format!("{}", value as i16),
+            ('d', MemAccessSize::Word) => format!("{}", value as i32),
+            ('c', _) => format!("{:?}", char::from(value as u8)),
+            _ => format!("{value}"),
+        };
+        line.push(' ');
+        line.push_str(&value);
+        addr = addr.wrapping_add(bytes);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(input: &str) -> (String, Vec<String>) {
+        let mut console = Console {
+            input: input.into(),
+            ..Console::default()
+        };
+        console.complete();
+        (console.input, console.lines.into())
+    }
+
+    #[test]
+    fn completion() {
+        assert_eq!(complete("sav"), ("savestate ".into(), vec![]));
+        assert_eq!(complete("prof"), ("profile ".into(), vec![]));
+        assert_eq!(complete("x"), ("x ".into(), vec![]));
+        assert_eq!(
+            complete("st"),
+            ("st".into(), vec!["stack  stats  step".into()])
+        );
+        assert_eq!(complete("zz"), ("zz".into(), vec![]));
+    }
+}
@@ -0,0 +1,570 @@
+mod console;
+mod source;
+mod terminal_display;
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, mpsc::Sender},
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+use console::Console;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, poll};
+use ratatui::{
+    Frame,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::Text,
+    widgets::{Block, Paragraph},
+};
+use source::Sources;
+pub use terminal_display::TerminalDisplay;
+
+use crate::{
+    cpu_thread::{
+        CpuHandle, CpuState,
+        cpu::ABI_NAMES,
+        crash_report::crash_report,
+        reverse::step_back,
+        stats::{SIZE_NAMES, Statistics},
+    },
+    debug_display::DebugDisplay,
+    device_log::DeviceLog,
+    display::{DisplayEvent, Resolution},
+    heap::{Heap, HeapState},
+    savestate,
+};
+
+pub struct Gui {
+    pub debug_display: DebugDisplay,
+    pub heap: Heap,
+    pub cpu_handle: Arc<Mutex<CpuHandle>>,
+    pub display: Sender<DisplayEvent>,
+    /// Resolution of the display, save states only load at the one they were saved at
+    pub resolution: Resolution,
+    /// Directory for save state slots
+    pub state_dir: PathBuf,
+    /// Updated by the statistics hook while it is enabled
+    pub stats: Arc<Mutex<Statistics>>,
+    pub device_log: DeviceLog,
+    /// Shows the display in a panel instead of a window
+    pub terminal_display: Option<TerminalDisplay>,
+}
+
+impl Gui {
+    /// Path of a save state slot, or `arg` itself if it isn't a slot number.
+    fn state_path(&self, arg: &str) -> PathBuf {
+        match arg.parse::<u32>() {
+            Ok(slot) => self.state_dir.join(format!("slot{slot}.state")),
+            Err(_) => arg.into(),
+        }
+    }
+}
+
+/// Index of the pc in the register selection
+const PC: usize = 32;
+
+/// Debugger state kept across frames
+struct Debugger {
+    /// Selected register, or [`PC`]
+    selected: usize,
+    /// Text typed so far while editing the selected register
+    input: Option<String>,
+    /// State before the last step or continue, used to highlight changes
+    previous: CpuState,
+    console: Console,
+    /// Shown instead of the other panels after the CPU faulted
+    crash: Option<Crash>,
+    /// Save state slot used by the hotkeys
+    slot: u32,
+    /// Panel shown above the console
+    view: View,
+    sources: Sources,
+}
+
+/// Panels that can be shown above the console, cycled with tab
+#[derive(Clone, Copy, PartialEq)]
+enum View {
+    Debug,
+    Source,
+    Statistics,
+    /// The display, with keys going to the guest
+    Display,
+}
+
+struct Crash {
+    report: String,
+    /// Path the report was saved to
+    saved: Option<String>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self {
+            selected: 0,
+            input: None,
+            previous: CpuState::default(),
+            console: Console::default(),
+            crash: None,
+            slot: 1,
+            view: View::Debug,
+            sources: Sources::default(),
+        }
+    }
+}
+
+impl Debugger {
+    /// Report a CPU fault, the CPU must be stopped.
+    fn fault(&mut self, cpu_handle: &mut CpuHandle, err: anyhow::Error) {
+        self.console.print(format!("error: {err:#}"));
+        if let Some(cpu) = cpu_handle.stopped_cpu() {
+            self.crash = Some(Crash {
+                report: crash_report(cpu, &err),
+                saved: None,
+            });
+        }
+    }
+}
+
+pub fn run(gui: Gui) -> JoinHandle<()> {
+    std::thread::Builder::new()
+        .name("gui".into())
+        .spawn(move || thread(gui))
+        .unwrap()
+}
+
+fn thread(mut gui: Gui) {
+    let mut terminal = ratatui::init();
+    let mut debugger = Debugger::default();
+    if gui.terminal_display.is_some() {
+        debugger.view = View::Display;
+    }
+    loop {
+        let (cpu, running, source) = {
+            let mut cpu = gui.cpu_handle.lock().unwrap();
+            match cpu.reap() {
+                Some(Ok(())) => debugger
+                    .console
+                    .print(format!("Stopped at 0x{:08X}", cpu.get_state().pc)),
+                Some(Err(err)) => debugger.fault(&mut cpu, err),
+                None => {}
+            }
+            cpu.request_update();
+            let source = match (debugger.view, cpu.stopped_cpu()) {
+                (View::Source, Some(stopped)) => Some(source::view(stopped, &mut debugger.sources)),
+                _ => None,
+            };
+            (cpu.get_state(), cpu.is_running(), source)
+        };
+
+        terminal
+            .draw(|frame| {
+                if let Some(crash) = &debugger.crash {
+                    crash_panel(frame, crash);
+                    return;
+                }
+                registers(frame, &gui, &debugger, &cpu, running);
+                match debugger.view {
+                    View::Debug => debug_display(frame, &mut gui),
+                    View::Source => source_panel(frame, source),
+                    View::Statistics => statistics(frame, &gui),
+                    View::Display => display_panel(frame, &gui),
+                }
+                console(frame, &debugger);
+            })
+            .expect("failed to draw frame");
+        if poll(Duration::from_millis(16)).unwrap() {
+            let event = event::read().expect("failed to read event");
+            if let Event::Key(KeyEvent {
+                code,
+                kind: KeyEventKind::Press,
+                ..
+            }) = event
+                && !handle_key(&gui, &mut debugger, code)
+            {
+                break;
+            }
+        }
+    }
+    ratatui::restore();
+    if let Some(display) = &gui.terminal_display
+        && let Err(err) = display.exit()
+    {
+        println!("Err: {:?}", err);
+    }
+}
+
+/// Handle a key press, returns `false` if the GUI should exit.
+fn handle_key(gui: &Gui, debugger: &mut Debugger, code: KeyCode) -> bool {
+    if let Some(crash) = &mut debugger.crash {
+        match code {
+            KeyCode::Esc => return false,
+            KeyCode::Enter => debugger.crash = None,
+            KeyCode::Char('w') => {
+                let secs = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let path = format!("crash-{secs}.txt");
+                match std::fs::write(&path, &crash.report) {
+                    Ok(()) => crash.saved = Some(path),
+                    Err(err) => crash.saved = Some(format!("failed to save: {err}")),
+                }
+            }
+            _ => {}
+        }
+        return true;
+    }
+
+    if debugger.console.focused {
+        match code {
+            KeyCode::Char(c) => debugger.console.push(c),
+            KeyCode::Backspace => debugger.console.pop(),
+            KeyCode::Enter => {
+                let line = debugger.console.submit();
+                console::execute(gui, debugger, &line);
+            }
+            KeyCode::Up => debugger.console.history_prev(),
+            KeyCode::Down => debugger.console.history_next(),
+            KeyCode::Tab => debugger.console.complete(),
+            KeyCode::Esc => debugger.console.focused = false,
+            _ => {}
+        }
+        return true;
+    }
+
+    if debugger.view == View::Display
+        && let Some(display) = &gui.terminal_display
+        && !matches!(code, KeyCode::Esc | KeyCode::Tab)
+    {
+        if let Err(err) = display.key(code) {
+            debugger.console.print(format!("error: {err:#}"));
+        }
+        return true;
+    }
+
+    let mut cpu_handle = gui.cpu_handle.lock().unwrap();
+
+    if let Some(input) = &mut debugger.input {
+        match code {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Enter => {
+                let value = parse_value(input);
+                debugger.input = None;
+                let Some(cpu) = cpu_handle.stopped_cpu() else {
+                    return true;
+                };
+                match (value, debugger.selected) {
+                    (Some(value), PC) => cpu.pc = value,
+                    (Some(value), reg) => cpu.write_register(reg, value),
+                    (None, _) => debugger.console.print("error: invalid value"),
+                }
+            }
+            KeyCode::Esc => debugger.input = None,
+            _ => {}
+        }
+        return true;
+    }
+
+    match code {
+        KeyCode::Esc => return false,
+        KeyCode::Up => debugger.selected = (debugger.selected + PC) % (PC + 1),
+        KeyCode::Down => debugger.selected = (debugger.selected + 1) % (PC + 1),
+        KeyCode::Char(':') => debugger.console.focused = true,
+        KeyCode::Tab => {
+            debugger.view = match debugger.view {
+                View::Debug => View::Source,
+                View::Source => View::Statistics,
+                View::Statistics if gui.terminal_display.is_some() => View::Display,
+                View::Statistics | View::Display => View::Debug,
+            }
+        }
+        KeyCode::Enter if !cpu_handle.is_running() => debugger.input = Some(String::new()),
+        KeyCode::Char(' ') => {
+            if cpu_handle.is_running() {
+                if let Err(err) = cpu_handle.stop() {
+                    debugger.fault(&mut cpu_handle, err);
+                }
+            } else {
+                debugger.previous = cpu_handle.get_state();
+                cpu_handle.start();
+            }
+        }
+        KeyCode::Char(c @ '1'..='9') => {
+            debugger.slot = c.to_digit(10).unwrap();
+            debugger
+                .console
+                .print(format!("Save state slot {}", debugger.slot));
+        }
+        KeyCode::Char('S') => {
+            let path = gui.state_path(&debugger.slot.to_string());
+            let result = cpu_handle
+                .with_cpu(|cpu| savestate::save(cpu, &gui.display, gui.resolution, &path))
+                .and_then(|result| result);
+            match result {
+                Ok(()) => debugger.console.print(format!("Saved {}", path.display())),
+                Err(err) => debugger.console.print(format!("error: {err:#}")),
+            }
+        }
+        KeyCode::Char('L') => {
+            let path = gui.state_path(&debugger.slot.to_string());
+            let result = cpu_handle
+                .with_cpu(|cpu| savestate::load(cpu, &gui.display, gui.resolution, &path))
+                .and_then(|result| result);
+            match result {
+                Ok(()) => debugger.console.print(format!("Loaded {}", path.display())),
+                Err(err) => debugger.console.print(format!("error: {err:#}")),
+            }
+        }
+        KeyCode::Char('b') if !cpu_handle.is_running() => {
+            debugger.previous = cpu_handle.get_state();
+            if let Some(cpu) = cpu_handle.stopped_cpu() {
+                match step_back(cpu) {
+                    Ok(true) => {}
+                    Ok(false) => debugger.console.print("Reached the start of the recording"),
+                    Err(err) => debugger.console.print(format!("error: {err:#}")),
+                }
+            }
+        }
+        KeyCode::Char('s') if !cpu_handle.is_running() => {
+            debugger.previous = cpu_handle.get_state();
+            if let Err(err) = cpu_handle.step() {
+                debugger.fault(&mut cpu_handle, err);
+            }
+        }
+        _ => {}
+    }
+    true
+}
+
+/// Parse a value typed by the user, either hex with a `0x` prefix or signed decimal.
+pub fn parse_value(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u32::from_str_radix(hex, 16).ok();
+    }
+    let value: i64 = s.parse().ok()?;
+    if (i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+        Some(value as u32)
+    } else {
+        None
+    }
+}
+
+const WIDTH: u16 = 17;
+const REGISTERS_WIDTH: u16 = 33;
+const REGISTERS_HEIGHT: u16 = 34;
+const CONSOLE_HEIGHT: u16 = 12;
+
+fn register_style(debugger: &Debugger, idx: usize, changed: bool) -> Style {
+    let mut style = Style::default();
+    if changed {
+        style = style.fg(Color::Yellow);
+    }
+    if idx == debugger.selected {
+        style = style.reversed();
+    }
+    style
+}
+
+fn registers(frame: &mut Frame<'_>, gui: &Gui, debugger: &Debugger, cpu: &CpuState, running: bool) {
+    let mut area = frame.area();
+    area.width = REGISTERS_WIDTH;
+    area.height = REGISTERS_HEIGHT;
+    let block = Block::bordered().title(if running {
+        "Registers [running]"
+    } else {
+        "Registers [paused]"
+    });
+
+    for (i, (&value, name)) in cpu.registers.iter().zip(ABI_NAMES).enumerate() {
+        let style = register_style(debugger, i, value != debugger.previous.registers[i]);
+        let text = match &debugger.input {
+            Some(input) if debugger.selected == i => {
+                format!("x{:<2} {:<4} {}", i, name, input)
+            }
+            _ => format!("x{:<2} {:<4} 0x{:08X} {:>11}", i, name, value, value as i32),
+        };
+        let area = {
+            let mut area = block.inner(area);
+            area.y += i as u16;
+            area
+        };
+        frame.render_widget(Text::styled(text, style), area);
+    }
+    frame.render_widget(block, area);
+
+    area.x += REGISTERS_WIDTH;
+    area.width = WIDTH;
+    area.height = 3;
+    let block = Block::bordered().title("PC");
+    let style = register_style(debugger, PC, cpu.pc != debugger.previous.pc);
+    let text = match &debugger.input {
+        Some(input) if debugger.selected == PC => input.clone(),
+        _ => format!("0x{:08X}", cpu.pc),
+    };
+    let text = Text::styled(text, style).right_aligned();
+    frame.render_widget(text, block.inner(area));
+    frame.render_widget(block, area);
+
+    area.y += 3;
+    let block = Block::bordered().title("Insn count");
+    let text = Text::raw(format!("{}", cpu.insn_count)).right_aligned();
+    frame.render_widget(text, block.inner(area));
+    frame.render_widget(block, area);
+
+    area.y += 3;
+    let block = Block::bordered().title("I/s");
+    let text = Text::raw(format!("{}", cpu.fps)).right_aligned();
+    frame.render_widget(text, block.inner(area));
+    frame.render_widget(block, area);
+
+    area.y += 3;
+    let block = Block::bordered().title("Stack");
+    let text = match cpu.stack {
+        Some(stack) => format!("{}/{}", stack.used(), stack.end - stack.start),
+        None => "-".into(),
+    };
+    frame.render_widget(Text::raw(text).right_aligned(), block.inner(area));
+    frame.render_widget(block, area);
+
+    area.y += 3;
+    area.height = REGISTERS_HEIGHT - 12;
+    let block = Block::bordered().title("Heap");
+    frame.render_widget(Text::raw(heap_lines(&gui.heap.read())), block.inner(area));
+    frame.render_widget(block, area);
+}
+
+/// Usage and the allocation size histogram, fitting the side column.
+fn heap_lines(heap: &HeapState) -> String {
+    let mut lines = vec![
+        format!("cur  {:>10}", heap.current),
+        format!("peak {:>10}", heap.peak),
+        format!("live {:>10}", heap.allocations.len()),
+    ];
+    if heap.reported != 0 {
+        lines.push(format!("used {:>10}", heap.reported));
+    }
+    if heap.histogram.iter().any(|&count| count != 0) {
+        lines.push(String::new());
+        lines.push(format!("{:>6} {:>8}", "size", "allocs"));
+        for (bucket, &count) in heap.histogram.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let size = match 1u64 << bucket {
+                size if size >= 1 << 20 => format!("{}M", size >> 20),
+                size if size >= 1 << 10 => format!("{}K", size >> 10),
+                size => size.to_string(),
+            };
+            lines.push(format!("<={size:>4} {count:>8}"));
+        }
+    }
+    lines.join("\n")
+}
+
+/// The panel and the console right of the registers, empty if the terminal is too small.
+fn right_column(frame: &Frame<'_>) -> [Rect; 2] {
+    let [_, column] = Layout::horizontal([
+        Constraint::Length(REGISTERS_WIDTH + WIDTH),
+        Constraint::Fill(1),
+    ])
+    .areas(frame.area());
+    Layout::vertical([Constraint::Fill(1), Constraint::Length(CONSOLE_HEIGHT)]).areas(column)
+}
+
+fn debug_display(frame: &mut Frame<'_>, gui: &mut Gui) {
+    let [area, _] = right_column(frame);
+    let block = Block::bordered().title("Debug");
+
+    gui.debug_display.update();
+    frame.render_widget(&gui.debug_display, block.inner(area));
+    frame.render_widget(block, area);
+}
+
+fn source_panel(frame: &mut Frame<'_>, source: Option<Text<'static>>) {
+    let [area, _] = right_column(frame);
+    let block = Block::bordered().title("Source");
+
+    let text = source.unwrap_or_else(|| Text::raw("Pause the CPU to see the source"));
+    frame.render_widget(Paragraph::new(text).block(block), area);
+}
+
+/// Number of mnemonics listed in the statistics panel
+const TOP_MNEMONICS: usize = 16;
+
+fn statistics(frame: &mut Frame<'_>, gui: &Gui) {
+    let [area, _] = right_column(frame);
+    let block = Block::bordered().title("Statistics");
+
+    let stats = gui.stats.lock().unwrap().clone();
+    let mut lines = vec![];
+    if stats.instructions == 0 {
+        lines.push("No statistics, use stats on".to_string());
+    } else {
+        lines.push(format!("Instructions  {}", stats.instructions));
+        lines.push(format!(
+            "Branches      {} taken, {} not taken",
+            stats.branches_taken, stats.branches_not_taken
+        ));
+        for (name, (loads, stores)) in SIZE_NAMES.iter().zip(stats.loads.iter().zip(stats.stores)) {
+            lines.push(format!("{name:<13} {loads} loads, {stores} stores"));
+        }
+        lines.push(String::new());
+        let mut devices: Vec<_> = stats.csr_accesses.iter().collect();
+        devices.sort();
+        for (name, (reads, writes)) in devices {
+            lines.push(format!("{name:<17} {reads:>10} reads {writes:>10} writes"));
+        }
+        lines.push(String::new());
+        for (name, count) in stats.top_mnemonics().into_iter().take(TOP_MNEMONICS) {
+            let percent = 100.0 * count as f64 / stats.instructions as f64;
+            lines.push(format!("{name:<7} {count:>12} {percent:>6.2}%"));
+        }
+    }
+
+    let text = Paragraph::new(lines.join("\n")).block(block);
+    frame.render_widget(text, area);
+}
+
+fn display_panel(frame: &mut Frame<'_>, gui: &Gui) {
+    let [area, _] = right_column(frame);
+    let block = Block::bordered().title("Display");
+
+    if let Some(display) = &gui.terminal_display {
+        frame.render_widget(display, block.inner(area));
+    }
+    frame.render_widget(block, area);
+}
+
+fn console(frame: &mut Frame<'_>, debugger: &Debugger) {
+    let [_, area] = right_column(frame);
+    let block = Block::bordered()
+        .title("Console")
+        .title_bottom(if debugger.console.focused {
+            "enter: run  tab: complete  esc: leave"
+        } else if debugger.view == View::Display {
+            "keys go to the guest  f12: screenshot  tab: view  esc: quit"
+        } else {
+            ": command  space: run/pause  s: step  b: step back  enter: edit  1-9 S L: save states  tab: view  esc: quit"
+        });
+
+    frame.render_widget(&debugger.console, block.inner(area));
+    frame.render_widget(block, area);
+}
+
+fn crash_panel(frame: &mut Frame<'_>, crash: &Crash) {
+    let block = Block::bordered()
+        .title("Crash report")
+        .title_bottom(match &crash.saved {
+            Some(saved) => format!("saved: {saved}"),
+            None => "w: save to file  enter: back to debugger  esc: quit".into(),
+        })
+        .red();
+    let report = Paragraph::new(crash.report.as_str()).block(block);
+    frame.render_widget(report, frame.area());
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use ratatui::{
+    style::{Color, Style, Stylize},
+    text::{Line, Text},
+};
+
+use crate::cpu_thread::{
+    cpu::Cpu,
+    disassembler::disassemble,
+    memory::MemAccessSize,
+    source::{locals, location},
+};
+
+/// Instructions shown before the pc
+const BEFORE: u32 = 3;
+/// Instructions shown from the pc on
+const AFTER: u32 = 6;
+/// Source lines shown around the current one
+const CONTEXT: u32 = 4;
+
+/// Source files read so far, `None` if they couldn't be read
+#[derive(Default)]
+pub struct Sources {
+    files: HashMap<String, Option<Vec<String>>>,
+}
+
+impl Sources {
+    fn get(&mut self, path: &str) -> Option<&[String]> {
+        self.files
+            .entry(path.into())
+            .or_insert_with(|| {
+                let text = std::fs::read_to_string(path).ok()?;
+                Some(text.lines().map(str::to_string).collect())
+            })
+            .as_deref()
+    }
+}
+
+/// Disassembly around the pc, the source around the current line and the locals.
+pub fn view(cpu: &Cpu, sources: &mut Sources) -> Text<'static> {
+    let mut text = Text::default();
+    let start = cpu.pc.saturating_sub(BEFORE * 4);
+    for addr in (start..cpu.pc.saturating_add(AFTER * 4)).step_by(4) {
+        let insn = match cpu.mem.read(addr, MemAccessSize::Word) {
+            Ok(insn) => disassemble(insn),
+            Err(_) => "??".into(),
+        };
+        let line = format!("0x{addr:08x} {:<24} {insn}", cpu.symbols.format(addr));
+        text.push_line(match addr == cpu.pc {
+            true => Line::styled(line, Style::default().reversed()),
+            false => Line::raw(line),
+        });
+    }
+
+    text.push_line("");
+    text.push_line(Line::styled(location(cpu), Style::default().bold()));
+    if let Some((file, current)) = cpu.symbols.lines.lookup(cpu.pc) {
+        match sources.get(file) {
+            Some(lines) => {
+                let first = current.saturating_sub(CONTEXT).max(1);
+                for number in first..=current + CONTEXT {
+                    let Some(source) = lines.get(number as usize - 1) else {
+                        break;
+                    };
+                    let line = format!("{number:>5} {source}");
+                    text.push_line(match number == current {
+                        true => Line::styled(line, Style::default().fg(Color::Yellow)),
+                        false => Line::raw(line),
+                    });
+                }
+            }
+            None => text.push_line(Line::raw("Source file not found").dim()),
+        }
+    }
+
+    let locals = locals(cpu);
+    if !locals.is_empty() {
+        text.push_line("");
+        text.push_line(Line::styled("Locals", Style::default().bold()));
+        for local in locals {
+            text.push_line(local);
+        }
+    }
+    text
+}
@@ -0,0 +1,209 @@
+use std::sync::{
+    Arc, Mutex, Weak,
+    atomic::{AtomicU64, Ordering},
+    mpsc::{Receiver, Sender},
+};
+
+use crossterm::event::KeyCode as TermKey;
+use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::Widget};
+use winit::keyboard::KeyCode;
+
+use crate::{
+    capture::Capture,
+    display::{DisplayEvent, FRAME_TIME, Screen},
+    keyboard::{KEYCODES_0_9, KEYCODES_A_Z, KeyInput},
+};
+
+/// Display state shared between the GUI and the thread presenting frames
+struct State {
+    screen: Screen,
+    capture: Capture,
+}
+
+/// The display shown in the terminal UI instead of a window, e.g. over SSH.
+///
+/// Frames are presented on a thread of their own, the GUI thread waits for the display
+/// when it saves a state.
+pub struct TerminalDisplay {
+    state: Arc<Mutex<State>>,
+    keyboard: Sender<KeyInput>,
+    /// Counts the presented frames like the display window does
+    frames: Arc<AtomicU64>,
+}
+
+impl TerminalDisplay {
+    pub fn new(
+        screen: Screen,
+        display: Receiver<DisplayEvent>,
+        keyboard: Sender<KeyInput>,
+        frames: Arc<AtomicU64>,
+        capture: Capture,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(State { screen, capture }));
+        let weak = Arc::downgrade(&state);
+        let counter = Arc::clone(&frames);
+        std::thread::Builder::new()
+            .name("terminal display".into())
+            .spawn(move || present(weak, display, counter))
+            .unwrap();
+        Self {
+            state,
+            keyboard,
+            frames,
+        }
+    }
+
+    /// Pass a key press to the guest, F12 saves a screenshot instead. Terminals don't
+    /// report releases, so the key is released right away.
+    pub fn key(&self, code: TermKey) -> anyhow::Result<()> {
+        if code == TermKey::F(12) {
+            let state = self.state.lock().unwrap();
+            let frame_number = self.frames.load(Ordering::Relaxed);
+            return state.capture.screenshot(state.screen.frame(), frame_number);
+        }
+        let Some((code, shift)) = keycode(code) else {
+            return Ok(());
+        };
+        let send = |code, pressed| {
+            let _ = self.keyboard.send(KeyInput { code, pressed });
+        };
+        if shift {
+            send(KeyCode::ShiftLeft, true);
+        }
+        send(code, true);
+        send(code, false);
+        if shift {
+            send(KeyCode::ShiftLeft, false);
+        }
+        Ok(())
+    }
+
+    /// Called when the GUI exits.
+    pub fn exit(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let State { screen, capture } = &mut *state;
+        capture.exit(screen.frame())
+    }
+}
+
+/// Apply the display events every frame, until the terminal display is dropped.
+fn present(state: Weak<Mutex<State>>, display: Receiver<DisplayEvent>, frames: Arc<AtomicU64>) {
+    while let Some(state) = state.upgrade() {
+        let mut state = state.lock().unwrap();
+        let State { screen, capture } = &mut *state;
+        screen.update(&display);
+        let frame_number = frames.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Err(err) = capture.presented(screen.frame(), frame_number) {
+            capture.recording = None;
+            eprintln!("recording stopped: {:?}", err);
+        }
+        drop(state);
+        std::thread::sleep(FRAME_TIME);
+    }
+}
+
+/// The key on a US layout that types `code`, and whether shift is held for it.
+fn keycode(code: TermKey) -> Option<(KeyCode, bool)> {
+    /// Shifted digits, starting at 0
+    const SHIFTED_DIGITS: &str = ")!@#$%^&*(";
+    /// Punctuation keys, unshifted and shifted
+    const PUNCTUATION: [(char, char, KeyCode); 11] = [
+        ('-', '_', KeyCode::Minus),
+        ('=', '+', KeyCode::Equal),
+        ('[', '{', KeyCode::BracketLeft),
+        (']', '}', KeyCode::BracketRight),
+        (';', ':', KeyCode::Semicolon),
+        ('\'', '"', KeyCode::Quote),
+        (',', '<', KeyCode::Comma),
+        ('.', '>', KeyCode::Period),
+        ('/', '?', KeyCode::Slash),
+        ('`', '~', KeyCode::Backquote),
+        ('\\', '|', KeyCode::Backslash),
+    ];
+
+    let c = match code {
+        TermKey::Enter => return Some((KeyCode::Enter, false)),
+        TermKey::Backspace => return Some((KeyCode::Backspace, false)),
+        TermKey::Up => return Some((KeyCode::ArrowUp, false)),
+        TermKey::Left => return Some((KeyCode::ArrowLeft, false)),
+        TermKey::Down => return Some((KeyCode::ArrowDown, false)),
+        TermKey::Right => return Some((KeyCode::ArrowRight, false)),
+        TermKey::Char(c) => c,
+        _ => return None,
+    };
+    match c {
+        ' ' => Some((KeyCode::Space, false)),
+        'a'..='z' => Some((KEYCODES_A_Z[c as usize - 'a' as usize], false)),
+        'A'..='Z' => Some((KEYCODES_A_Z[c as usize - 'A' as usize], true)),
+        '0'..='9' => Some((KEYCODES_0_9[c as usize - '0' as usize], false)),
+        _ => {
+            if let Some(i) = SHIFTED_DIGITS.find(c) {
+                return Some((KEYCODES_0_9[i], true));
+            }
+            PUNCTUATION.iter().find_map(|&(plain, shifted, code)| {
+                (c == plain || c == shifted).then_some((code, c == shifted))
+            })
+        }
+    }
+}
+
+/// Draws the frame with half block characters, two pixels per cell. The frame is
+/// scaled down to fit, keeping its aspect ratio, and every pixel is the average of
+/// the frame pixels it covers.
+impl Widget for &TerminalDisplay {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let state = self.state.lock().unwrap();
+        let resolution = state.screen.resolution();
+        let frame = state.screen.frame();
+        let (width, height) = (resolution.width as u32, resolution.height as u32);
+        let (cells_x, pixels_y) = (area.width as u32, area.height as u32 * 2);
+        if cells_x == 0 || pixels_y == 0 {
+            return;
+        }
+        // Frame pixels per terminal pixel, never scaled up
+        let scale = (width as f64 / cells_x as f64)
+            .max(height as f64 / pixels_y as f64)
+            .max(1.0);
+        let out_width = (width as f64 / scale) as u32;
+        let out_height = (height as f64 / scale) as u32;
+        // Centered, in cells
+        let left = area.x as u32 + (cells_x - out_width) / 2;
+        let top = area.y as u32 + (pixels_y - out_height) / 4;
+
+        // Average color of the frame pixels covered by terminal pixel (x, y)
+        let average = |x: u32, y: u32| {
+            let x0 = (x as f64 * scale) as u32;
+            let y0 = (y as f64 * scale) as u32;
+            let x1 = (((x + 1) as f64 * scale) as u32).clamp(x0 + 1, width);
+            let y1 = (((y + 1) as f64 * scale) as u32).clamp(y0 + 1, height);
+            let mut sum = [0u32; 3];
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    let idx = (sy * width + sx) as usize * 4;
+                    for (total, &channel) in sum.iter_mut().zip(&frame[idx..idx + 3]) {
+                        *total += channel as u32;
+                    }
+                }
+            }
+            let count = (x1 - x0) * (y1 - y0);
+            Color::Rgb(
+                (sum[0] / count) as u8,
+                (sum[1] / count) as u8,
+                (sum[2] / count) as u8,
+            )
+        };
+
+        for y in (0..out_height).step_by(2) {
+            for x in 0..out_width {
+                let position = ((left + x) as u16, (top + y / 2) as u16);
+                let Some(cell) = buf.cell_mut(position) else {
+                    continue;
+                };
+                cell.set_char('▀').set_fg(average(x, y));
+                if y + 1 < out_height {
+                    cell.set_bg(average(x, y + 1));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, Sender},
+    },
+};
+
+use anyhow::{Context, Error, Result, bail};
+use winit::keyboard::KeyCode;
+
+use crate::{
+    capture::{self, Capture},
+    cpu_thread::CpuHandle,
+    display::{DisplayEvent, Screen},
+    keyboard::{self, KeyInput},
+};
+
+/// A line of a headless script
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Run this many frames
+    Wait(u64),
+    Press(KeyCode),
+    Release(KeyCode),
+    /// Press and release
+    Key(KeyCode),
+    /// Save the current frame, .png or .ppm
+    Screenshot(PathBuf),
+    Quit,
+}
+
+/// Parse a script, one command per line, `#` starts a comment.
+///
+/// ```text
+/// wait 60
+/// key enter
+/// screenshot out/menu.png
+/// ```
+pub fn parse_script(text: &str) -> Result<Vec<Command>> {
+    let mut commands = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let command = parse_command(line).with_context(|| format!("[headless] line {}", i + 1))?;
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+pub fn load_script(path: &Path) -> Result<Vec<Command>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    parse_script(&text)
+}
+
+fn parse_command(line: &str) -> Result<Command> {
+    let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.trim();
+    let key = || keyboard::keycode_from_name(arg).with_context(|| format!("unknown key {arg:?}"));
+    Ok(match name {
+        "wait" => Command::Wait(arg.parse().context("expected a number of frames")?),
+        "press" => Command::Press(key()?),
+        "release" => Command::Release(key()?),
+        "key" => Command::Key(key()?),
+        "screenshot" => {
+            let path = PathBuf::from(arg);
+            capture::ImageFormat::from_path(&path)?;
+            Command::Screenshot(path)
+        }
+        "quit" => Command::Quit,
+        _ => bail!("unknown command {name:?}"),
+    })
+}
+
+/// Runs the machine without a window or terminal UI, e.g. on CI machines without a
+/// display server or GPU.
+///
+/// The CPU runs on this thread instead of its own, a fixed number of instructions per
+/// frame, so runs of the same script are identical however busy the machine is.
+pub struct Headless {
+    pub screen: Screen,
+    pub display: Receiver<DisplayEvent>,
+    pub keyboard: Sender<KeyInput>,
+    /// Counts the presented frames like the display window does
+    pub frames: Arc<AtomicU64>,
+    pub capture: Capture,
+    /// The CPU thread must not be started
+    pub cpu_handle: Arc<Mutex<CpuHandle>>,
+    pub insns_per_frame: u64,
+    /// Error the CPU stopped with
+    pub cpu_error: Option<Error>,
+}
+
+impl Headless {
+    /// Run `script`, or until the CPU stops without one. Returns the error the CPU
+    /// stopped with, if any.
+    pub fn run(mut self, script: Option<Vec<Command>>) -> Result<()> {
+        match script {
+            Some(script) => self.run_script(script)?,
+            None => {
+                while self.cpu_error.is_none() {
+                    self.frame();
+                }
+            }
+        }
+        self.screen.update(&self.display);
+        self.capture.exit(self.screen.frame())?;
+        match self.cpu_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn run_script(&mut self, script: Vec<Command>) -> Result<()> {
+        for command in script {
+            match command {
+                Command::Wait(frames) => {
+                    // Nothing changes once the CPU stopped, the rest of the script still
+                    // runs so screenshots show where it stopped
+                    for _ in 0..frames {
+                        if self.cpu_error.is_some() {
+                            break;
+                        }
+                        self.frame();
+                    }
+                }
+                Command::Press(code) => self.key(code, true),
+                Command::Release(code) => self.key(code, false),
+                Command::Key(code) => {
+                    self.key(code, true);
+                    self.key(code, false);
+                }
+                Command::Screenshot(path) => {
+                    self.screen.update(&self.display);
+                    capture::save_image(&path, self.screen.resolution(), self.screen.frame())?;
+                }
+                Command::Quit => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn key(&self, code: KeyCode, pressed: bool) {
+        self.keyboard.send(KeyInput { code, pressed }).unwrap();
+    }
+
+    /// Run the CPU for a frame and present it.
+    fn frame(&mut self) {
+        let mut cpu_handle = self.cpu_handle.lock().unwrap();
+        let cpu = cpu_handle.stopped_cpu().unwrap();
+        for _ in 0..self.insns_per_frame {
+            if let Err(err) = cpu.tick() {
+                self.cpu_error = Some(err);
+                break;
+            }
+        }
+        cpu.publish();
+        drop(cpu_handle);
+        self.screen.update(&self.display);
+        let frame_number = self.frames.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Err(err) = self.capture.presented(self.screen.frame(), frame_number) {
+            eprintln!("recording stopped: {:?}", err);
+            self.capture.recording = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script() {
+        let script = "\
+            # open the menu\n\
+            wait 60\n\
+            \n\
+            press shift  # held\n\
+            key a\n\
+            release   shift\n\
+            screenshot out/menu.png\n\
+            quit\n";
+        assert_eq!(
+            parse_script(script).unwrap(),
+            [
+                Command::Wait(60),
+                Command::Press(KeyCode::ShiftLeft),
+                Command::Key(KeyCode::KeyA),
+                Command::Release(KeyCode::ShiftLeft),
+                Command::Screenshot(PathBuf::from("out/menu.png")),
+                Command::Quit,
+            ]
+        );
+    }
+
+    #[test]
+    fn script_errors() {
+        for (script, message) in [
+            ("wait", "expected a number of frames"),
+            ("wait -1", "expected a number of frames"),
+            ("key f13", "unknown key \"f13\""),
+            ("press", "unknown key \"\""),
+            ("screenshot out/menu.gif", "unknown image format"),
+            ("jump 3", "unknown command \"jump\""),
+        ] {
+            let err = format!(
+                "{:#}",
+                parse_script(&format!("wait 1\n{script}")).unwrap_err()
+            );
+            assert!(err.starts_with("[headless] line 2: "), "{err}");
+            assert!(err.contains(message), "{err}");
+        }
+    }
+}
@@ -1,43 +1,204 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicU32, Ordering},
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex},
 };
 
 use anyhow::{Result, bail};
 
-use crate::csrs::Csr;
+use crate::{
+    cpu_thread::memory::Memory,
+    csrs::Csr,
+    savestate::{StateReader, StateWriter},
+};
+
+/// Allocation sizes are counted in buckets of powers of two, larger ones go to the last
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+/// Allocations reported by the guest allocator
+#[derive(Default, Clone)]
+pub struct HeapState {
+    /// Heap usage written by the guest, for allocators that don't report allocations
+    pub reported: u32,
+    /// Live allocations, address to size
+    pub allocations: BTreeMap<u32, u32>,
+    /// Freed addresses that weren't allocated again, to tell double frees apart
+    freed: HashSet<u32>,
+    pub current: u64,
+    pub peak: u64,
+    /// Number of allocations by size, bucket `n` counts sizes up to `2^n` bytes
+    pub histogram: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl HeapState {
+    fn alloc(&mut self, mem: &mut Memory, addr: u32, size: u32) -> Result<()> {
+        let end = addr.saturating_add(size);
+        if let Some((&prev, &prev_size)) = self.allocations.range(..end).next_back()
+            && prev.saturating_add(prev_size) > addr
+        {
+            bail!(
+                "[heap] allocation of {size} bytes at 0x{addr:08x} overlaps the live allocation at 0x{prev:08x}"
+            );
+        }
+        self.allocations.insert(addr, size);
+        self.freed.remove(&addr);
+        self.current += size as u64;
+        self.peak = self.peak.max(self.current);
+        let bucket =
+            (32 - size.saturating_sub(1).leading_zeros() as usize).min(HISTOGRAM_BUCKETS - 1);
+        self.histogram[bucket] += 1;
+        mem.poison(addr, size, false);
+        Ok(())
+    }
+
+    fn free(&mut self, mem: &mut Memory, addr: u32) -> Result<()> {
+        // Like free(NULL)
+        if addr == 0 {
+            return Ok(());
+        }
+        let Some(size) = self.allocations.remove(&addr) else {
+            if self.freed.contains(&addr) {
+                bail!("[heap] double free of 0x{addr:08x}");
+            }
+            bail!("[heap] free of unknown pointer 0x{addr:08x}");
+        };
+        self.freed.insert(addr);
+        self.current -= size as u64;
+        mem.poison(addr, size, true);
+        Ok(())
+    }
+
+    /// Live allocations, which are leaks once the program exits.
+    pub fn leaks(&self) -> Vec<String> {
+        if self.allocations.is_empty() {
+            return vec![];
+        }
+        let mut lines = vec![format!(
+            "Leaked {} bytes in {} allocations:",
+            self.current,
+            self.allocations.len()
+        )];
+        for (addr, size) in &self.allocations {
+            lines.push(format!("  0x{addr:08x} {size} bytes"));
+        }
+        lines
+    }
+}
 
 pub struct Heap {
-    value: Arc<AtomicU32>,
+    state: Arc<Mutex<HeapState>>,
 }
 
 impl Heap {
-    pub fn new(value: Arc<AtomicU32>) -> Self {
-        Self { value }
+    pub fn new(state: Arc<Mutex<HeapState>>) -> Self {
+        Self { state }
     }
 
-    pub fn read(&self) -> u32 {
-        self.value.load(Ordering::SeqCst)
+    pub fn read(&self) -> HeapState {
+        self.state.lock().unwrap().clone()
     }
 }
 
+/// Allocation tracking device.
+///
+/// - 1112: write the heap usage, for allocators that don't report allocations
+/// - 1113: write the size of the next allocation
+/// - 1114: write the address of an allocation of the size written before
+/// - 1115: write an address that is freed
+/// - 1116/1117: write the start/end of the heap, it is poisoned except for live allocations
+/// - 1118: write 0 to stop checking accesses to poisoned memory, e.g. inside the allocator,
+///   anything else to check them again
 pub struct HeapCsr {
-    value: Arc<AtomicU32>,
+    state: Arc<Mutex<HeapState>>,
+    size: u32,
+    heap_start: u32,
 }
 
 impl HeapCsr {
-    pub fn new(value: Arc<AtomicU32>) -> Self {
-        Self { value }
+    pub fn new(state: Arc<Mutex<HeapState>>) -> Self {
+        Self {
+            state,
+            size: 0,
+            heap_start: 0,
+        }
     }
 }
 
 impl Csr for HeapCsr {
-    fn read(&mut self, _csr: u32, _ram: &mut [u8]) -> Result<u32> {
+    fn name(&self) -> &'static str {
+        "heap"
+    }
+
+    fn read(&mut self, _csr: u32, _mem: &mut Memory) -> Result<u32> {
         bail!("Can't read heap")
     }
 
-    fn write(&mut self, _csr: u32, _ram: &mut [u8], data: u32) -> Result<()> {
-        self.value.store(data, Ordering::SeqCst);
+    fn write(&mut self, csr: u32, mem: &mut Memory, data: u32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match csr {
+            1112 => state.reported = data,
+            1113 => self.size = data,
+            1114 => state.alloc(mem, data, self.size)?,
+            1115 => state.free(mem, data)?,
+            1116 => self.heap_start = data,
+            1117 => {
+                mem.poison(self.heap_start, data.saturating_sub(self.heap_start), true);
+                for (&addr, &size) in &state.allocations {
+                    mem.poison(addr, size, false);
+                }
+            }
+            1118 => mem.checks = data != 0,
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.size = 0;
+        self.heap_start = 0;
+        *self.state.lock().unwrap() = HeapState::default();
+    }
+
+    fn save(&mut self, w: &mut StateWriter) {
+        let state = self.state.lock().unwrap();
+        w.u32(state.reported);
+        w.u32(self.size);
+        w.u32(self.heap_start);
+        w.u64(state.current);
+        w.u64(state.peak);
+        w.u32(state.allocations.len() as u32);
+        for (&addr, &size) in &state.allocations {
+            w.u32(addr);
+            w.u32(size);
+        }
+        w.u32(state.freed.len() as u32);
+        for &addr in &state.freed {
+            w.u32(addr);
+        }
+        for &count in &state.histogram {
+            w.u64(count);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader, _mem: &Memory) -> Result<()> {
+        let mut state = HeapState {
+            reported: r.u32()?,
+            ..Default::default()
+        };
+        self.size = r.u32()?;
+        self.heap_start = r.u32()?;
+        state.current = r.u64()?;
+        state.peak = r.u64()?;
+        for _ in 0..r.u32()? {
+            let addr = r.u32()?;
+            state.allocations.insert(addr, r.u32()?);
+        }
+        for _ in 0..r.u32()? {
+            state.freed.insert(r.u32()?);
+        }
+        for count in &mut state.histogram {
+            *count = r.u64()?;
+        }
+        *self.state.lock().unwrap() = state;
         Ok(())
     }
 }
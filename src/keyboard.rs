@@ -1,11 +1,15 @@
-use std::sync::mpsc::Receiver;
+use std::{collections::VecDeque, sync::mpsc::Receiver};
 
 use winit::{
     event::{ElementState, KeyEvent},
     keyboard::{KeyCode, PhysicalKey},
 };
 
-use crate::csrs::Csr;
+use crate::{
+    cpu_thread::memory::Memory,
+    csrs::Csr,
+    savestate::{StateReader, StateWriter},
+};
 
 pub const KEY_SPACE: u8 = 0x20;
 
@@ -69,40 +73,87 @@ pub const KEY_SLASH: u8 = 0x2F;
 pub const KEY_TILDE: u8 = 0x60;
 pub const KEY_BACKSLASH: u8 = 0x5C;
 
+/// A key going down or up, from the display window or a script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyInput {
+    pub code: KeyCode,
+    pub pressed: bool,
+}
+
+impl KeyInput {
+    /// `None` for keys winit couldn't identify.
+    pub fn from_event(event: &KeyEvent) -> Option<Self> {
+        let PhysicalKey::Code(code) = event.physical_key else {
+            return None;
+        };
+        Some(Self {
+            code,
+            pressed: event.state == ElementState::Pressed,
+        })
+    }
+}
+
 pub struct KeyboardCsr {
-    recv: Receiver<KeyEvent>,
+    recv: Receiver<KeyInput>,
+    /// Key codes taken from `recv` but not read by the guest yet
+    pending: VecDeque<u8>,
 }
 
 impl KeyboardCsr {
-    pub fn new(recv: Receiver<KeyEvent>) -> Self {
-        Self { recv }
+    pub fn new(recv: Receiver<KeyInput>) -> Self {
+        Self {
+            recv,
+            pending: VecDeque::new(),
+        }
     }
 
-    pub fn read_key(&self) -> u8 {
-        let Ok(event) = self.recv.try_recv() else {
-            return 0;
-        };
-        let PhysicalKey::Code(code) = event.physical_key else {
+    pub fn read_key(&mut self) -> u8 {
+        if let Some(code) = self.pending.pop_front() {
+            return code;
+        }
+        let Ok(key) = self.recv.try_recv() else {
             return 0;
         };
+        key_to_u8(key)
+    }
+}
 
-        let mut code = keycode_to_u8(code);
-        if code == 0 {
-            return 0;
-        }
-        if event.state == ElementState::Pressed {
-            code |= 0x80;
-        }
-        code
+fn key_to_u8(key: KeyInput) -> u8 {
+    let mut code = keycode_to_u8(key.code);
+    if code == 0 {
+        return 0;
+    }
+    if key.pressed {
+        code |= 0x80;
     }
+    code
 }
 
 impl Csr for KeyboardCsr {
-    fn read(&mut self, _csr: u32, _ram: &mut [u8]) -> anyhow::Result<u32> {
+    fn name(&self) -> &'static str {
+        "keyboard"
+    }
+
+    fn read(&mut self, _csr: u32, _mem: &mut Memory) -> anyhow::Result<u32> {
         Ok(self.read_key() as u32)
     }
 
-    fn write(&mut self, _csr: u32, _ram: &mut [u8], _data: u32) -> anyhow::Result<()> {
+    fn write(&mut self, _csr: u32, _mem: &mut Memory, _data: u32) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn save(&mut self, w: &mut StateWriter) {
+        for key in self.recv.try_iter() {
+            let code = key_to_u8(key);
+            if code != 0 {
+                self.pending.push_back(code);
+            }
+        }
+        w.bytes(self.pending.make_contiguous());
+    }
+
+    fn load(&mut self, r: &mut StateReader, _mem: &Memory) -> anyhow::Result<()> {
+        self.pending = r.bytes()?.iter().copied().collect();
         Ok(())
     }
 }
@@ -167,3 +218,98 @@ pub fn keycode_to_u8(key: KeyCode) -> u8 {
         _ => 0,
     }
 }
+
+/// Key code for a name in scripts, the names of the `KEY_` constants in lower case,
+/// e.g. `a`, `0`, `enter` or `lbracket`.
+pub fn keycode_from_name(name: &str) -> Option<KeyCode> {
+    if let [c] = name.as_bytes() {
+        return match c {
+            b'a'..=b'z' => KEYCODES_A_Z.get((c - b'a') as usize).copied(),
+            b'0'..=b'9' => KEYCODES_0_9.get((c - b'0') as usize).copied(),
+            _ => None,
+        };
+    }
+    Some(match name {
+        "space" => KeyCode::Space,
+        "shift" => KeyCode::ShiftLeft,
+        "enter" => KeyCode::Enter,
+        "up" => KeyCode::ArrowUp,
+        "left" => KeyCode::ArrowLeft,
+        "down" => KeyCode::ArrowDown,
+        "right" => KeyCode::ArrowRight,
+        "backspace" => KeyCode::Backspace,
+        "minus" => KeyCode::Minus,
+        "equals" => KeyCode::Equal,
+        "lbracket" => KeyCode::BracketLeft,
+        "rbracket" => KeyCode::BracketRight,
+        "semicolon" => KeyCode::Semicolon,
+        "apostrophe" => KeyCode::Quote,
+        "comma" => KeyCode::Comma,
+        "period" => KeyCode::Period,
+        "slash" => KeyCode::Slash,
+        "tilde" => KeyCode::Backquote,
+        "backslash" => KeyCode::Backslash,
+        _ => return None,
+    })
+}
+
+pub(crate) const KEYCODES_0_9: [KeyCode; 10] = [
+    KeyCode::Digit0,
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+    KeyCode::Digit9,
+];
+
+pub(crate) const KEYCODES_A_Z: [KeyCode; 26] = [
+    KeyCode::KeyA,
+    KeyCode::KeyB,
+    KeyCode::KeyC,
+    KeyCode::KeyD,
+    KeyCode::KeyE,
+    KeyCode::KeyF,
+    KeyCode::KeyG,
+    KeyCode::KeyH,
+    KeyCode::KeyI,
+    KeyCode::KeyJ,
+    KeyCode::KeyK,
+    KeyCode::KeyL,
+    KeyCode::KeyM,
+    KeyCode::KeyN,
+    KeyCode::KeyO,
+    KeyCode::KeyP,
+    KeyCode::KeyQ,
+    KeyCode::KeyR,
+    KeyCode::KeyS,
+    KeyCode::KeyT,
+    KeyCode::KeyU,
+    KeyCode::KeyV,
+    KeyCode::KeyW,
+    KeyCode::KeyX,
+    KeyCode::KeyY,
+    KeyCode::KeyZ,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names() {
+        assert_eq!(keycode_from_name("a"), Some(KeyCode::KeyA));
+        assert_eq!(keycode_from_name("z"), Some(KeyCode::KeyZ));
+        assert_eq!(keycode_from_name("0"), Some(KeyCode::Digit0));
+        assert_eq!(keycode_from_name("9"), Some(KeyCode::Digit9));
+        assert_eq!(keycode_from_name("enter"), Some(KeyCode::Enter));
+        assert_eq!(keycode_from_name("lbracket"), Some(KeyCode::BracketLeft));
+        assert_eq!(keycode_from_name("tilde"), Some(KeyCode::Backquote));
+        for name in ["", "A", "-", "Enter", "f1", "aa"] {
+            assert_eq!(keycode_from_name(name), None, "{name:?}");
+        }
+    }
+}
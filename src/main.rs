@@ -1,27 +1,54 @@
+pub mod capture;
 pub mod character_printer;
 pub mod cpu_thread;
 pub mod csrs;
 pub mod ddi;
 pub mod debug_display;
+pub mod device_log;
 pub mod display;
+pub mod dwarf;
+pub mod elf;
+pub mod framebuffer;
 pub mod gui;
+pub mod headless;
 pub mod heap;
 pub mod keyboard;
+pub mod savestate;
 
-use std::sync::{Arc, Mutex, atomic::AtomicU32, mpsc::channel};
+use std::{
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex, atomic::AtomicU64, mpsc::channel},
+};
 
+use capture::{Capture, ImageFormat, Recording};
 use character_printer::CharacterPrinterCsr;
-use cpu_thread::cpu::Cpu;
+use cpu_thread::cpu::{Cpu, Stack};
 use csrs::Csrs;
 use ddi::DdiCsr;
 use debug_display::{DebugDisplay, DebugDisplayCsr};
-use gui::Gui;
-use heap::{Heap, HeapCsr};
+use device_log::DeviceLog;
+use display::{Resolution, Screen};
+use framebuffer::DrawTarget;
+use gui::{Gui, TerminalDisplay};
+use headless::Headless;
+use heap::{Heap, HeapCsr, HeapState};
 
+use anyhow::{Context, Result, bail};
 use clap::Parser;
 use keyboard::KeyboardCsr;
 
-use crate::cpu_thread::CpuHandle;
+use crate::{
+    cpu_thread::{
+        CpuHandle,
+        coverage::Coverage,
+        profiler::Profiler,
+        reverse::{Recorder, budget_from_mib},
+        stats::{Statistics, StatsHook},
+        trace::Tracer,
+    },
+    elf::Elf,
+};
 
 #[derive(Parser)]
 struct Args {
@@ -29,9 +56,128 @@ struct Args {
     persist_ram: Option<String>,
     #[arg(long)]
     flash: Option<String>,
+    /// Load an ELF file, its symbols are used by the profiler and debugger
+    #[arg(long)]
+    elf: Option<String>,
+    /// Log every executed instruction to this file in Spike's commit log format
+    #[arg(long)]
+    trace: Option<String>,
+    /// Only trace instructions with a pc in `start-end`
+    #[arg(long, value_parser = parse_range)]
+    trace_range: Option<Range<u32>>,
+    /// Don't trace the first N instructions
+    #[arg(long, default_value_t = 0)]
+    trace_skip: u64,
+    /// Stop tracing after N instructions
+    #[arg(long)]
+    trace_limit: Option<u64>,
+    /// Record execution for stepping backwards, using at most this many MiB
+    #[arg(long, value_parser = parse_mib)]
+    record: Option<usize>,
+    /// Profile the guest, writing `<prefix>.txt` and `<prefix>.folded` at exit
+    #[arg(long)]
+    profile: Option<PathBuf>,
+    /// Count instructions and device accesses, writing them as JSON at exit
+    #[arg(long)]
+    stats: Option<PathBuf>,
+    /// Stack region as start-end, `sp` must stay in it. Defaults to the region between
+    /// the `_stack_start` and `_stack_end` symbols of the ELF file
+    #[arg(long, value_parser = parse_range)]
+    stack: Option<Range<u32>>,
+    /// Report loads of memory that was never flashed or written
+    #[arg(long)]
+    check_uninit: bool,
+    /// Stop at the first load of uninitialized memory instead of reporting it
+    #[arg(long, requires = "check_uninit")]
+    stop_on_uninit: bool,
+    /// Fill RAM with this byte instead of zeros, e.g. 0xA5
+    #[arg(long, value_parser = parse_byte, default_value = "0")]
+    ram_fill: u8,
+    /// Record guest code coverage, writing `<prefix>.info` (lcov) and `<prefix>.txt` at exit
+    #[arg(long)]
+    coverage: Option<PathBuf>,
+    /// Load a save state at startup
+    #[arg(long)]
+    load_state: Option<PathBuf>,
+    /// Directory for save state slots
+    #[arg(long, default_value = "states")]
+    state_dir: PathBuf,
+    /// Screen resolution as WIDTHxHEIGHT
+    #[arg(long, value_parser = parse_resolution, default_value_t = Resolution::DEFAULT)]
+    resolution: Resolution,
+    /// Save the last frame to this file when the display closes, .png or .ppm
+    #[arg(long, value_parser = parse_image_path)]
+    screenshot: Option<PathBuf>,
+    /// Directory the F12 key saves screenshots to
+    #[arg(long, default_value = "screenshots")]
+    screenshot_dir: PathBuf,
+    /// Save presented frames to this directory as a numbered image sequence. Frames are
+    /// written in the background, the display slows down when the disk can't keep up
+    #[arg(long)]
+    record_frames: Option<PathBuf>,
+    /// Only record every Nth frame
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 1)]
+    record_every: u64,
+    /// Image format of recorded frames
+    #[arg(long, value_enum, default_value_t = ImageFormat::Png)]
+    record_format: ImageFormat,
+    /// Run without the display window and the terminal UI, until the CPU stops or the
+    /// script ends. Exits with status 1 if the CPU stopped with an error
+    #[arg(long, conflicts_with = "terminal_display")]
+    headless: bool,
+    /// Instructions run per frame in headless mode, which doesn't run in real time
+    #[arg(long, requires = "headless", value_parser = clap::value_parser!(u64).range(1..), default_value_t = 100_000)]
+    insns_per_frame: u64,
+    /// Script for headless runs with one command per line: `wait FRAMES`,
+    /// `press KEY`, `release KEY`, `key KEY`, `screenshot PATH` or `quit`
+    #[arg(long, requires = "headless")]
+    script: Option<PathBuf>,
+    /// Show the display in the terminal UI instead of a window, e.g. over SSH
+    #[arg(long)]
+    terminal_display: bool,
+    /// Initial window size as a multiple of the resolution
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=16), default_value_t = 2)]
+    scale: u16,
+}
+
+fn parse_range(s: &str) -> Result<Range<u32>> {
+    let (start, end) = s.split_once('-').context("expected start-end")?;
+    let start = gui::parse_value(start).context("invalid start")?;
+    let end = gui::parse_value(end).context("invalid end")?;
+    if start > end {
+        bail!("the start must be below the end");
+    }
+    Ok(start..end)
+}
+
+fn parse_byte(s: &str) -> Result<u8> {
+    let value = gui::parse_value(s).context("invalid value")?;
+    u8::try_from(value).context("expected a byte")
+}
+
+fn parse_mib(s: &str) -> Result<usize> {
+    budget_from_mib(s.parse().context("invalid size")?)
+}
+
+fn parse_resolution(s: &str) -> Result<Resolution> {
+    let (width, height) = s.split_once('x').context("expected WIDTHxHEIGHT")?;
+    let width = width.parse().context("invalid width")?;
+    let height = height.parse().context("invalid height")?;
+    let range = 1..=Resolution::MAX;
+    if !range.contains(&width) || !range.contains(&height) {
+        bail!("width and height must be between 1 and {}", Resolution::MAX);
+    }
+    Ok(Resolution { width, height })
+}
+
+fn parse_image_path(s: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(s);
+    ImageFormat::from_path(&path)?;
+    Ok(path)
 }
 
 fn main() {
+    let args = Args::parse();
     let mut csrs = Csrs::new();
 
     // Debug display
@@ -44,25 +190,28 @@ fn main() {
     };
 
     // Heap
-    let heap = {
-        let value = Arc::new(AtomicU32::new(0));
-        let heap = Heap::new(Arc::clone(&value));
-        let heap_csr = HeapCsr::new(value);
-        csrs.insert_csr(&[1112], Box::new(heap_csr));
-        heap
+    let (heap, heap_state) = {
+        let state = Arc::new(Mutex::new(HeapState::default()));
+        let heap = Heap::new(Arc::clone(&state));
+        let heap_csr = HeapCsr::new(Arc::clone(&state));
+        csrs.insert_csr(
+            &[1112, 1113, 1114, 1115, 1116, 1117, 1118],
+            Box::new(heap_csr),
+        );
+        (heap, state)
     };
 
     // Display (ddi, character)
-    let display = {
+    let device_log = DeviceLog::default();
+    let frames = Arc::new(AtomicU64::new(0));
+    let (display, display_send) = {
         let (send, recv) = channel();
-        let ddi = DdiCsr::new(send.clone());
-        csrs.insert_csr(
-            &[1050, 1051, 1052, 1053, 1054, 1055, 1056, 1057, 1058, 1059],
-            Box::new(ddi),
-        );
-        let c = CharacterPrinterCsr::new(send);
-        csrs.insert_csr(&[1024, 1025, 1026, 1037], Box::new(c));
-        recv
+        let output = DrawTarget::new(send.clone(), device_log.clone(), args.resolution);
+        let ddi = DdiCsr::new(output.clone(), Arc::clone(&frames));
+        csrs.insert_csr(&(1050..=1083).collect::<Vec<_>>(), Box::new(ddi));
+        let c = CharacterPrinterCsr::new(output);
+        csrs.insert_csr(&[1024, 1025, 1026, 1027, 1028, 1037], Box::new(c));
+        (recv, send)
     };
 
     // Keyboard
@@ -73,32 +222,158 @@ fn main() {
         send
     };
 
-    let args = Args::parse();
     let mut cpu = Cpu::new(csrs);
+    cpu.mem.fill = args.ram_fill;
+    if args.check_uninit {
+        cpu.mem.track_initialized();
+    }
+    cpu.stop_on_uninitialized = args.stop_on_uninit;
+    cpu.device_log = device_log.clone();
+    cpu.mem.clear();
 
     if let Some(flash) = args.flash {
         let data = std::fs::read(flash).unwrap();
         cpu.flash(&data);
     }
 
+    if let Some(elf) = args.elf {
+        let data = std::fs::read(elf).unwrap();
+        let elf = Elf::parse(&data, cpu.mem.vec.len()).unwrap();
+        if let Some(warning) = &elf.warning {
+            println!("Warning: {warning}");
+        }
+        cpu.load_elf(elf).unwrap();
+    }
+
+    if let Some(stack) = args.stack {
+        cpu.stack = Some(Stack::new(stack.start, stack.end));
+    }
+
+    if let Some(trace) = args.trace {
+        let mut tracer = Tracer::new(&trace).unwrap();
+        tracer.range = args.trace_range;
+        tracer.skip = args.trace_skip;
+        tracer.limit = args.trace_limit;
+        cpu.add_hook(Box::new(tracer));
+    }
+
+    if let Some(prefix) = args.profile {
+        cpu.add_hook(Box::new(Profiler::new(prefix)));
+    }
+
+    if let Some(prefix) = args.coverage {
+        cpu.add_hook(Box::new(Coverage::new(prefix)));
+    }
+
+    let stats = Arc::new(Mutex::new(Statistics::default()));
+    if let Some(path) = args.stats {
+        cpu.add_hook(Box::new(StatsHook::new(Arc::clone(&stats), Some(path))));
+    }
+
+    if let Some(budget) = args.record {
+        cpu.add_hook(Box::new(Recorder::new(budget)));
+    }
+
+    let mut capture = Capture::new(args.resolution, Arc::clone(&cpu.shared_insn_count));
+    capture.screenshot_dir = args.screenshot_dir;
+    capture.exit_screenshot = args.screenshot;
+    capture.recording = args.record_frames.map(|dir| Recording {
+        dir,
+        format: args.record_format,
+        every: args.record_every,
+    });
+
+    if let Some(path) = args.load_state {
+        savestate::load(&mut cpu, &display_send, args.resolution, &path).unwrap();
+    }
+
+    let script = args
+        .script
+        .map(|path| headless::load_script(&path).unwrap());
+
     let cpu_handle = {
         let mut cpu_handle = CpuHandle::new(cpu);
-        cpu_handle.start();
+        // Headless mode runs the CPU itself
+        if !args.headless {
+            cpu_handle.start();
+        }
         Arc::new(Mutex::new(cpu_handle))
     };
 
-    let gui = Gui {
-        debug_display,
-        heap,
-        cpu_handle: Arc::clone(&cpu_handle),
-    };
-    let gui_handle = gui::run(gui);
-    //gui_handle.join().unwrap();
-    display::run(display, keyboard);
-    cpu_handle.lock().unwrap().request_stop();
+    let mut failed = false;
+    if args.headless {
+        let headless = Headless {
+            screen: Screen::new(args.resolution),
+            display,
+            keyboard,
+            frames,
+            capture,
+            cpu_handle: Arc::clone(&cpu_handle),
+            insns_per_frame: args.insns_per_frame,
+            cpu_error: None,
+        };
+        if let Err(err) = headless.run(script) {
+            println!("Err: {:?}", err);
+            failed = true;
+        }
+    } else {
+        let mut gui = Gui {
+            debug_display,
+            heap,
+            cpu_handle: Arc::clone(&cpu_handle),
+            display: display_send,
+            resolution: args.resolution,
+            state_dir: args.state_dir,
+            stats,
+            device_log: device_log.clone(),
+            terminal_display: None,
+        };
+        if args.terminal_display {
+            let screen = Screen::new(args.resolution);
+            gui.terminal_display = Some(TerminalDisplay::new(
+                screen, display, keyboard, frames, capture,
+            ));
+            gui::run(gui).join().unwrap();
+            cpu_handle.lock().unwrap().request_stop();
+        } else {
+            let gui_handle = gui::run(gui);
+            display::run(
+                display,
+                keyboard,
+                frames,
+                args.resolution,
+                args.scale,
+                capture,
+            );
+            cpu_handle.lock().unwrap().request_stop();
+            gui_handle.join().unwrap();
+        }
+    }
 
-    gui_handle.join().unwrap();
-    if let Err(err) = cpu_handle.lock().unwrap().stop() {
+    let mut cpu_handle = cpu_handle.lock().unwrap();
+    if let Err(err) = cpu_handle.stop() {
         println!("Err: {:?}", err);
+        failed = true;
+    }
+    if let Some(cpu) = cpu_handle.stopped_cpu() {
+        if let Err(err) = cpu.finish() {
+            println!("Err: {:?}", err);
+            failed = true;
+        }
+        for (pc, (addr, count)) in &cpu.uninitialized_reads {
+            println!(
+                "Uninitialized read at {}: {count}x, first at 0x{addr:08x}",
+                cpu.symbols.format(*pc)
+            );
+        }
+    }
+    for line in heap_state.lock().unwrap().leaks() {
+        println!("{line}");
+    }
+    for line in device_log.lines() {
+        println!("Warning: {line}");
+    }
+    if failed && args.headless {
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,250 @@
+use std::{
+    path::Path,
+    sync::mpsc::{Sender, channel},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    cpu_thread::cpu::{Cpu, Trap},
+    display::{DisplayEvent, Resolution},
+};
+
+const MAGIC: &[u8; 8] = b"BOBBYSAV";
+/// Version of the layout below. Bumped once per release that changes it, states of other
+/// versions are rejected rather than migrated.
+///
+/// Byte strings are prefixed with their length as a u32.
+/// - header: [`MAGIC`], version, display width and height as u32
+/// - cpu: 32 registers and the pc as u32, the instruction count as u64, then `mstatus`,
+///   `mtvec`, `mepc`, `mcause` and the pending interrupt flag as u32
+/// - RAM as a byte string
+/// - shadow memory: the poisoned bitmap, the heap check flag as u32 and the initialized
+///   bitmap, each bitmap a byte string of little endian u64 words, empty when unused
+/// - devices: one byte string holding the device count as u32 and then a byte string per
+///   device, in the order they were inserted
+/// - display: the RGBA frame and the palette index of every pixel as byte strings
+const VERSION: u32 = 1;
+
+/// Serializes machine state, all values are little endian.
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Write a length prefixed byte string.
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+}
+
+/// Reads values written by [`StateWriter`].
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("[savestate] unexpected end of data");
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Save the CPU, memory, devices and the display framebuffer to `path`.
+///
+/// The CPU must be stopped so that the framebuffer matches the rest of the state.
+pub fn save(
+    cpu: &mut Cpu,
+    display: &Sender<DisplayEvent>,
+    resolution: Resolution,
+    path: &Path,
+) -> Result<()> {
+    let mut w = StateWriter::new();
+    w.buf.extend_from_slice(MAGIC);
+    w.u32(VERSION);
+    w.u32(resolution.width as u32);
+    w.u32(resolution.height as u32);
+
+    for &reg in &cpu.registers {
+        w.u32(reg);
+    }
+    w.u32(cpu.pc);
+    w.u64(cpu.insn_count);
+    let trap = cpu.trap;
+    w.u32(trap.mstatus());
+    w.u32(trap.mtvec);
+    w.u32(trap.mepc);
+    w.u32(trap.mcause);
+    w.u32(trap.pending as u32);
+    w.bytes(&cpu.mem.vec);
+    cpu.mem.save_shadow(&mut w);
+    let mut devices = StateWriter::new();
+    cpu.csrs.save(&mut devices);
+    w.bytes(&devices.into_inner());
+
+    let (reply, frame) = channel();
+    display
+        .send(DisplayEvent::Snapshot { reply })
+        .context("display is closed")?;
+    let (frame, indices) = frame
+        .recv_timeout(Duration::from_secs(1))
+        .context("display did not respond")?;
+    w.bytes(&frame);
+    w.bytes(&indices);
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, w.into_inner())
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Load a state written by [`save`], with the display at the same `resolution`.
+pub fn load(
+    cpu: &mut Cpu,
+    display: &Sender<DisplayEvent>,
+    resolution: Resolution,
+    path: &Path,
+) -> Result<()> {
+    let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut r = StateReader::new(&data);
+    if r.take(MAGIC.len())? != MAGIC {
+        bail!("[savestate] {} is not a save state", path.display());
+    }
+    let version = r.u32()?;
+    if version != VERSION {
+        bail!("[savestate] unsupported version {version}, expected {VERSION}");
+    }
+    let (width, height) = (r.u32()?, r.u32()?);
+    if (width, height) != (resolution.width as u32, resolution.height as u32) {
+        bail!("[savestate] saved at a resolution of {width}x{height}, not {resolution}");
+    }
+
+    let mut registers = [0; 32];
+    for reg in &mut registers {
+        *reg = r.u32()?;
+    }
+    let pc = r.u32()?;
+    let insn_count = r.u64()?;
+    let mut trap = Trap::default();
+    trap.set_mstatus(r.u32()?);
+    trap.mtvec = r.u32()?;
+    trap.mepc = r.u32()?;
+    trap.mcause = r.u32()?;
+    trap.pending = r.u32()? != 0;
+    let mem = r.bytes()?;
+    if mem.len() != cpu.mem.vec.len() {
+        bail!("[savestate] memory size mismatch");
+    }
+    let shadow = cpu.mem.read_shadow(&mut r)?;
+    let devices = r.bytes()?;
+    let frame = r.bytes()?.to_vec();
+    let indices = r.bytes()?.to_vec();
+
+    // Devices can still reject their state, they go back to their previous state then
+    // and nothing else has changed yet
+    cpu.csrs.load(&mut StateReader::new(devices), &cpu.mem)?;
+    cpu.reset_cpu();
+    cpu.registers = registers;
+    cpu.pc = pc;
+    cpu.insn_count = insn_count;
+    cpu.trap = trap;
+    cpu.mem.vec.copy_from_slice(mem);
+    cpu.mem.load_shadow(shadow);
+    display
+        .send(DisplayEvent::Restore { frame, indices })
+        .context("display is closed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use super::*;
+    use crate::csrs::Csrs;
+
+    /// Answers snapshots like the display does
+    fn display(events: Receiver<DisplayEvent>) {
+        for event in events {
+            if let DisplayEvent::Snapshot { reply } = event {
+                reply.send((vec![1, 2, 3, 4], vec![])).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn broken_state_leaves_the_machine_alone() {
+        let (send, events) = channel();
+        std::thread::spawn(move || display(events));
+        let path = std::env::temp_dir().join(format!("bobby-{}.state", std::process::id()));
+
+        let mut cpu = Cpu::new(Csrs::new());
+        cpu.registers[5] = 7;
+        cpu.pc = 0x100;
+        save(&mut cpu, &send, Resolution::DEFAULT, &path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+
+        cpu.registers[5] = 9;
+        cpu.mem.vec[0x20] = 0xAB;
+        std::fs::write(&path, &data[..data.len() - 2]).unwrap();
+        assert!(load(&mut cpu, &send, Resolution::DEFAULT, &path).is_err());
+        assert_eq!(cpu.registers[5], 9);
+        assert_eq!(cpu.mem.vec[0x20], 0xAB);
+
+        std::fs::write(&path, &data).unwrap();
+        load(&mut cpu, &send, Resolution::DEFAULT, &path).unwrap();
+        assert_eq!(cpu.registers[5], 7);
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.mem.vec[0x20], 0);
+
+        let other = Resolution {
+            width: 320,
+            height: 200,
+        };
+        cpu.registers[5] = 9;
+        assert!(load(&mut cpu, &send, other, &path).is_err());
+        assert_eq!(cpu.registers[5], 9);
+        std::fs::remove_file(&path).unwrap();
+    }
+}